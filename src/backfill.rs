@@ -0,0 +1,223 @@
+// Chunked, resumable initial loads. The table is split into primary key or ctid ranges once,
+// the plan is stored in transform.sync_backfill_ranges on the target and every range is marked
+// completed after its copy, so an interrupted backfill carries on with the first unfinished one.
+// The first and last range are open ended to pick up rows outside of the planned bounds.
+// ctid ranges are only read with TID range scans from PostgreSQL 14 on, older servers scan the
+// whole table for every range.
+use crate::catalog::primary_key_columns;
+use crate::config::{BackfillConfig, SplitBy};
+use crate::cursor::{self, CursorType};
+use crate::sql::quote_ident;
+use sqlx::{PgConnection, PgExecutor, PgPool, Row};
+use std::error::Error;
+use tracing::{info, warn};
+
+const DEFAULT_KEYS_PER_RANGE: i64 = 1_000_000;
+const DEFAULT_PAGES_PER_RANGE: i64 = 10_000;
+// server_version_num of the first release with TID range scans
+const TID_RANGE_SCAN_VERSION: i32 = 140_000;
+
+pub struct BackfillRange {
+    pub range_no: i32,
+    pub predicate: String,
+}
+
+pub async fn ensure_backfill_table(target_pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query("CREATE SCHEMA IF NOT EXISTS transform").execute(target_pool).await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS transform.sync_backfill_ranges (
+            table_name text NOT NULL,
+            range_no int NOT NULL,
+            predicate text NOT NULL,
+            completed_at timestamptz,
+            PRIMARY KEY (table_name, range_no)
+        )",
+    )
+    .execute(target_pool)
+    .await?;
+    Ok(())
+}
+
+// Predicates for [start, end] cut into `chunk` sized ranges (chunk > 0), `bound` renders a boundary value
+pub(crate) fn split_ranges(expr: &str, start: i64, end: i64, chunk: i64, bound: impl Fn(i64) -> String) -> Vec<BackfillRange> {
+    debug_assert!(chunk > 0);
+    let mut predicates = Vec::new();
+    let mut lower = start;
+    while lower <= end {
+        let upper = lower.saturating_add(chunk);
+        let first = lower == start;
+        // a saturated upper bound can't move on, the range is open ended instead
+        let last = upper > end || upper == i64::MAX;
+        let predicate = match (first, last) {
+            (true, true) => "TRUE".to_string(),
+            (true, false) => format!("{} < {}", expr, bound(upper)),
+            (false, true) => format!("{} >= {}", expr, bound(lower)),
+            (false, false) => format!("{} >= {} AND {} < {}", expr, bound(lower), expr, bound(upper)),
+        };
+        predicates.push(predicate);
+        if last {
+            break;
+        }
+        lower = upper;
+    }
+    if predicates.is_empty() {
+        predicates.push("TRUE".to_string());
+    }
+    predicates
+        .into_iter()
+        .enumerate()
+        .map(|(i, predicate)| BackfillRange { range_no: i as i32, predicate })
+        .collect()
+}
+
+async fn plan_primary_key_ranges(source_pool: &PgPool, table_name: &str, column: &str, chunk: i64) -> Result<Vec<BackfillRange>, Box<dyn Error>> {
+    let row = sqlx::query(&format!(
+        "SELECT MIN({col})::bigint, MAX({col})::bigint FROM {}",
+        table_name,
+        col = quote_ident(column)
+    ))
+    .fetch_one(source_pool)
+    .await?;
+    let (min, max): (Option<i64>, Option<i64>) = (row.try_get(0)?, row.try_get(1)?);
+    match (min, max) {
+        (Some(min), Some(max)) => Ok(split_ranges(&quote_ident(column), min, max, chunk, |v| v.to_string())),
+        // empty table, a single range still picks up whatever gets inserted until it runs
+        _ => Ok(vec![BackfillRange { range_no: 0, predicate: "TRUE".to_string() }]),
+    }
+}
+
+async fn plan_ctid_ranges(source_pool: &PgPool, table_name: &str, chunk: i64) -> Result<Vec<BackfillRange>, Box<dyn Error>> {
+    let pages: i64 = sqlx::query_scalar("SELECT pg_relation_size($1::regclass) / current_setting('block_size')::bigint")
+        .bind(table_name)
+        .fetch_one(source_pool)
+        .await?;
+    let version: i32 = sqlx::query_scalar("SELECT current_setting('server_version_num')::int").fetch_one(source_pool).await?;
+    if version < TID_RANGE_SCAN_VERSION {
+        warn!(table = %table_name, "the source has no TID range scans before PostgreSQL 14, every ctid range scans the whole table");
+    }
+    Ok(split_ranges("ctid", 0, pages - 1, chunk, |page| format!("'({},0)'::tid", page)))
+}
+
+pub async fn plan_ranges(source_pool: &PgPool, table_name: &str, config: &BackfillConfig) -> Result<Vec<BackfillRange>, Box<dyn Error>> {
+    if let Some(chunk_size) = config.chunk_size.filter(|&size| size <= 0) {
+        return Err(format!("backfill chunk_size of table {} must be positive, got {}", table_name, chunk_size).into());
+    }
+    if config.split_by == SplitBy::PrimaryKey {
+        let key = primary_key_columns(source_pool, table_name).await?;
+        if let [column] = key.as_slice() {
+            if cursor::column_type(source_pool, table_name, column).await? == Some(CursorType::Integer) {
+                let chunk = config.chunk_size.unwrap_or(DEFAULT_KEYS_PER_RANGE);
+                return plan_primary_key_ranges(source_pool, table_name, column, chunk).await;
+            }
+        }
+        info!(table = %table_name, "no single integer primary key, splitting the backfill by ctid");
+    }
+    let chunk = config.chunk_size.unwrap_or(DEFAULT_PAGES_PER_RANGE);
+    plan_ctid_ranges(source_pool, table_name, chunk).await
+}
+
+pub async fn has_plan(target_pool: &PgPool, table_name: &str) -> Result<bool, Box<dyn Error>> {
+    let planned: i64 = sqlx::query_scalar("SELECT count(*) FROM transform.sync_backfill_ranges WHERE table_name = $1")
+        .bind(table_name)
+        .fetch_one(target_pool)
+        .await?;
+    Ok(planned > 0)
+}
+
+// A backfill is complete once it has been planned and every range is done
+pub async fn is_complete(target_pool: &PgPool, table_name: &str) -> Result<bool, Box<dyn Error>> {
+    let row = sqlx::query(
+        "SELECT count(*) AS planned, count(*) FILTER (WHERE completed_at IS NULL) AS pending
+         FROM transform.sync_backfill_ranges WHERE table_name = $1",
+    )
+    .bind(table_name)
+    .fetch_one(target_pool)
+    .await?;
+    let (planned, pending): (i64, i64) = (row.try_get("planned")?, row.try_get("pending")?);
+    Ok(planned > 0 && pending == 0)
+}
+
+// Run in the transaction that saves the xmin watermark or cursor condition the incremental syncs
+// start from, a plan only exists together with it
+pub async fn save_plan(conn: &mut PgConnection, table_name: &str, ranges: &[BackfillRange]) -> Result<(), Box<dyn Error>> {
+    for range in ranges {
+        sqlx::query("INSERT INTO transform.sync_backfill_ranges (table_name, range_no, predicate) VALUES ($1, $2, $3)")
+            .bind(table_name)
+            .bind(range.range_no)
+            .bind(&range.predicate)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn pending_ranges(target_pool: &PgPool, table_name: &str) -> Result<Vec<BackfillRange>, Box<dyn Error>> {
+    let rows = sqlx::query(
+        "SELECT range_no, predicate FROM transform.sync_backfill_ranges
+         WHERE table_name = $1 AND completed_at IS NULL ORDER BY range_no",
+    )
+    .bind(table_name)
+    .fetch_all(target_pool)
+    .await?;
+    let mut ranges = Vec::with_capacity(rows.len());
+    for row in rows {
+        ranges.push(BackfillRange { range_no: row.try_get("range_no")?, predicate: row.try_get("predicate")? });
+    }
+    Ok(ranges)
+}
+
+// Run in the transaction that loads the range, so a range is only marked done with its rows committed
+pub async fn complete_range<'e>(executor: impl PgExecutor<'e>, table_name: &str, range_no: i32) -> Result<(), Box<dyn Error>> {
+    sqlx::query("UPDATE transform.sync_backfill_ranges SET completed_at = now() WHERE table_name = $1 AND range_no = $2")
+        .bind(table_name)
+        .bind(range_no)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn predicates(start: i64, end: i64, chunk: i64) -> Vec<String> {
+        split_ranges("id", start, end, chunk, |v| v.to_string()).into_iter().map(|r| r.predicate).collect()
+    }
+
+    #[test]
+    fn first_and_last_ranges_are_open_ended() {
+        assert_eq!(predicates(1, 25, 10), vec!["id < 11", "id >= 11 AND id < 21", "id >= 21"]);
+    }
+
+    #[test]
+    fn ranges_are_numbered_in_order() {
+        let ranges = split_ranges("id", 0, 99, 10, |v| v.to_string());
+        assert_eq!(ranges.iter().map(|r| r.range_no).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn single_range_covers_everything() {
+        assert_eq!(predicates(5, 5, 10), vec!["TRUE"]);
+        assert_eq!(predicates(0, 9, 10), vec!["TRUE"]);
+    }
+
+    #[test]
+    fn empty_ctid_range_still_has_a_range() {
+        let ranges = split_ranges("ctid", 0, -1, 10, |page| format!("'({},0)'::tid", page));
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].predicate, "TRUE");
+    }
+
+    #[test]
+    fn range_ending_at_max_terminates() {
+        assert_eq!(
+            predicates(i64::MAX - 25, i64::MAX, 10),
+            vec![
+                format!("id < {}", i64::MAX - 15),
+                format!("id >= {} AND id < {}", i64::MAX - 15, i64::MAX - 5),
+                format!("id >= {}", i64::MAX - 5),
+            ]
+        );
+        assert_eq!(predicates(i64::MIN, i64::MAX, i64::MAX).len(), 3);
+    }
+}
@@ -0,0 +1,53 @@
+// Catalog lookups shared by the different sync modes
+use crate::config::TableConfig;
+use sqlx::PgPool;
+use std::error::Error;
+
+// Primary key columns of a table in key order, empty if the table has none.
+// `table` is a quoted (optionally schema-qualified) name.
+pub async fn primary_key_columns(pool: &PgPool, table: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let columns: Vec<String> = sqlx::query_scalar(
+        "SELECT a.attname::text
+         FROM pg_index i
+         CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY AS k(attnum, position)
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+         WHERE i.indrelid = $1::regclass AND i.indisprimary
+         ORDER BY k.position",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    Ok(columns)
+}
+
+// Column names of a table in attribute order
+pub async fn column_names(pool: &PgPool, table: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let columns: Vec<String> = sqlx::query_scalar(
+        "SELECT attname::text FROM pg_attribute
+         WHERE attrelid = $1::regclass AND attnum > 0 AND NOT attisdropped
+         ORDER BY attnum",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    Ok(columns)
+}
+
+// Columns kept by the table's include/exclude lists in attribute order,
+// None when nothing is left out and the table can be copied with SELECT *
+pub async fn selected_columns(pool: &PgPool, table: &TableConfig) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let columns = column_names(pool, &table.name).await?;
+    if let Some(include) = &table.include_columns {
+        if let Some(missing) = include.iter().find(|c| !c.ends_with('*') && !columns.contains(c)) {
+            return Err(format!("Included column {}.{} does not exist", table.name, missing).into());
+        }
+    }
+    let selected: Vec<String> = columns.iter().filter(|c| table.selects_column(c)).cloned().collect();
+    if selected.is_empty() {
+        return Err(format!("Column selection of {} leaves no columns to copy", table.name).into());
+    }
+    if selected.len() == columns.len() {
+        return Ok(None);
+    }
+    Ok(Some(selected))
+}
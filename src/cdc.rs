@@ -0,0 +1,825 @@
+// Change data capture through a logical replication slot.
+// The slot is read with pg_logical_slot_peek_*_changes over a regular connection, every
+// decoded source transaction is applied to the target in its own transaction together with
+// the checkpointed LSN, and only then the slot is advanced. After a crash we either re-read
+// transactions that the target already has (skipped by the checkpoint) or ones it doesn't.
+use crate::config::{CdcConfig, OutputPlugin, SyncConfig};
+use crate::sql::{quote_ident, quote_qualified};
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, Row, Transaction as PgTransaction};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TableRef {
+    pub schema: String,
+    pub name: String,
+}
+
+impl TableRef {
+    // Table name from the config, unqualified names live in `public`
+    pub fn parse(name: &str) -> Self {
+        match name.split_once('.') {
+            Some((schema, name)) => TableRef { schema: schema.to_string(), name: name.to_string() },
+            None => TableRef { schema: "public".to_string(), name: name.to_string() },
+        }
+    }
+
+    pub fn quoted(&self) -> String {
+        format!("{}.{}", quote_ident(&self.schema), quote_ident(&self.name))
+    }
+}
+
+// column name -> text representation (None for NULL)
+pub type ColumnValues = Vec<(String, Option<String>)>;
+
+#[derive(Debug)]
+pub enum Change {
+    Insert { table: TableRef, new: ColumnValues },
+    // `new` only holds the columns that were sent (unchanged TOAST values are left out)
+    Update { table: TableRef, key: ColumnValues, new: ColumnValues },
+    Delete { table: TableRef, key: ColumnValues },
+    Truncate { tables: Vec<TableRef> },
+}
+
+#[derive(Debug, Default)]
+pub struct SourceTransaction {
+    pub end_lsn: u64,
+    pub changes: Vec<Change>,
+}
+
+pub fn parse_lsn(lsn: &str) -> Result<u64, Box<dyn Error>> {
+    let (hi, lo) = lsn.split_once('/').ok_or_else(|| format!("Invalid LSN {}", lsn))?;
+    let hi = u64::from_str_radix(hi, 16).map_err(|e| format!("Invalid LSN {}: {}", lsn, e))?;
+    let lo = u64::from_str_radix(lo, 16).map_err(|e| format!("Invalid LSN {}: {}", lsn, e))?;
+    Ok((hi << 32) | lo)
+}
+
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+async fn ensure_publication(source_pool: &PgPool, cdc: &CdcConfig, tables: &[&str]) -> Result<(), Box<dyn Error>> {
+    let exists: Option<String> = sqlx::query_scalar("SELECT pubname::text FROM pg_publication WHERE pubname = $1")
+        .bind(&cdc.publication)
+        .fetch_optional(source_pool)
+        .await?;
+    if exists.is_some() {
+        info!(publication = %cdc.publication, "publication already exists");
+        return Ok(());
+    }
+    let table_list = tables.iter().map(|t| quote_qualified(t)).collect::<Vec<_>>().join(", ");
+    let create = format!("CREATE PUBLICATION {} FOR TABLE {}", quote_ident(&cdc.publication), table_list);
+    info!(publication = %cdc.publication, sql = %create, "creating publication");
+    sqlx::query(&create).execute(source_pool).await?;
+    Ok(())
+}
+
+async fn ensure_slot(source_pool: &PgPool, cdc: &CdcConfig) -> Result<(), Box<dyn Error>> {
+    let exists: Option<String> = sqlx::query_scalar("SELECT slot_name::text FROM pg_replication_slots WHERE slot_name = $1")
+        .bind(&cdc.slot)
+        .fetch_optional(source_pool)
+        .await?;
+    if exists.is_some() {
+        info!(slot = %cdc.slot, "replication slot already exists");
+        return Ok(());
+    }
+    let plugin = match cdc.plugin {
+        OutputPlugin::Pgoutput => "pgoutput",
+        OutputPlugin::Wal2json => "wal2json",
+    };
+    let lsn: String = sqlx::query_scalar("SELECT lsn::text FROM pg_create_logical_replication_slot($1, $2)")
+        .bind(&cdc.slot)
+        .bind(plugin)
+        .fetch_one(source_pool)
+        .await?;
+    // Changes committed before this LSN are not in the slot, the tables need a regular sync after this point
+    info!(slot = %cdc.slot, plugin = %plugin, lsn = %lsn, "created replication slot");
+    Ok(())
+}
+
+async fn ensure_checkpoint_table(target_pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query("CREATE SCHEMA IF NOT EXISTS transform").execute(target_pool).await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS transform.sync_cdc_checkpoint (
+            slot_name text PRIMARY KEY,
+            confirmed_lsn pg_lsn NOT NULL,
+            updated_at timestamptz NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(target_pool)
+    .await?;
+    Ok(())
+}
+
+async fn load_checkpoint(target_pool: &PgPool, slot: &str) -> Result<u64, Box<dyn Error>> {
+    let lsn: Option<String> = sqlx::query_scalar("SELECT confirmed_lsn::text FROM transform.sync_cdc_checkpoint WHERE slot_name = $1")
+        .bind(slot)
+        .fetch_optional(target_pool)
+        .await?;
+    match lsn {
+        Some(lsn) => parse_lsn(&lsn),
+        None => Ok(0),
+    }
+}
+
+// Minimal reader over a pgoutput message
+struct MessageReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        MessageReader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or("Truncated pgoutput message")?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, Box<dyn Error>> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, Box<dyn Error>> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn cstr(&mut self) -> Result<String, Box<dyn Error>> {
+        let rest = &self.data[self.pos..];
+        let end = rest.iter().position(|b| *b == 0).ok_or("Unterminated string in pgoutput message")?;
+        let s = String::from_utf8(rest[..end].to_vec())?;
+        self.pos += end + 1;
+        Ok(s)
+    }
+}
+
+struct Relation {
+    table: TableRef,
+    // (column name, part of the replica identity)
+    columns: Vec<(String, bool)>,
+}
+
+// Decodes pgoutput (proto_version 1) messages into transactions.
+// Relation messages are cached across calls since a session only sends each one once.
+#[derive(Default)]
+pub struct PgoutputDecoder {
+    relations: HashMap<u32, Relation>,
+    current: Option<SourceTransaction>,
+}
+
+// One value per column: None = unchanged TOAST value, Some(None) = NULL
+type Tuple = Vec<Option<Option<String>>>;
+
+impl PgoutputDecoder {
+    fn read_tuple(reader: &mut MessageReader) -> Result<Tuple, Box<dyn Error>> {
+        let ncols = reader.i16()?;
+        let mut values = Vec::with_capacity(ncols as usize);
+        for _ in 0..ncols {
+            match reader.u8()? {
+                b'n' => values.push(Some(None)),
+                b'u' => values.push(None),
+                b't' => {
+                    let len = usize::try_from(reader.i32()?).map_err(|_| "Negative value length in pgoutput message")?;
+                    let text = String::from_utf8(reader.bytes(len)?.to_vec())?;
+                    values.push(Some(Some(text)));
+                }
+                other => return Err(format!("Unsupported tuple value kind {:?}", other as char).into()),
+            }
+        }
+        Ok(values)
+    }
+
+    fn relation(&self, relid: u32) -> Result<&Relation, Box<dyn Error>> {
+        self.relations
+            .get(&relid)
+            .ok_or_else(|| format!("Change for unknown relation {}", relid).into())
+    }
+
+    // all sent columns, skipping unchanged TOAST values
+    fn tuple_values(relation: &Relation, tuple: &Tuple) -> ColumnValues {
+        relation
+            .columns
+            .iter()
+            .zip(tuple)
+            .filter_map(|((name, _), value)| value.clone().map(|v| (name.clone(), v)))
+            .collect()
+    }
+
+    // only the replica identity columns
+    fn key_values(relation: &Relation, tuple: &Tuple) -> ColumnValues {
+        relation
+            .columns
+            .iter()
+            .zip(tuple)
+            .filter(|((_, is_key), _)| *is_key)
+            .filter_map(|((name, _), value)| value.clone().map(|v| (name.clone(), v)))
+            .collect()
+    }
+
+    fn push_change(&mut self, change: Change) -> Result<(), Box<dyn Error>> {
+        match self.current.as_mut() {
+            Some(tx) => {
+                tx.changes.push(change);
+                Ok(())
+            }
+            None => Err("pgoutput change outside of a transaction".into()),
+        }
+    }
+
+    // Feed one message, returns the transaction when its commit message arrives
+    pub fn decode(&mut self, data: &[u8]) -> Result<Option<SourceTransaction>, Box<dyn Error>> {
+        let mut reader = MessageReader::new(data);
+        match reader.u8()? {
+            b'B' => {
+                self.current = Some(SourceTransaction::default());
+            }
+            b'C' => {
+                let _flags = reader.u8()?;
+                let _commit_lsn = reader.u64()?;
+                let end_lsn = reader.u64()?;
+                let mut tx = self.current.take().ok_or("pgoutput commit without begin")?;
+                tx.end_lsn = end_lsn;
+                return Ok(Some(tx));
+            }
+            b'R' => {
+                let relid = reader.u32()?;
+                let schema = reader.cstr()?;
+                let name = reader.cstr()?;
+                let _replica_identity = reader.u8()?;
+                let ncols = reader.i16()?;
+                let mut columns = Vec::with_capacity(ncols as usize);
+                for _ in 0..ncols {
+                    let flags = reader.u8()?;
+                    let column_name = reader.cstr()?;
+                    let _type_oid = reader.u32()?;
+                    let _type_modifier = reader.i32()?;
+                    columns.push((column_name, flags & 1 == 1));
+                }
+                self.relations.insert(relid, Relation { table: TableRef { schema, name }, columns });
+            }
+            b'I' => {
+                let relid = reader.u32()?;
+                let _new_marker = reader.u8()?;
+                let tuple = Self::read_tuple(&mut reader)?;
+                let relation = self.relation(relid)?;
+                let change = Change::Insert {
+                    table: relation.table.clone(),
+                    new: Self::tuple_values(relation, &tuple),
+                };
+                self.push_change(change)?;
+            }
+            b'U' => {
+                let relid = reader.u32()?;
+                let mut marker = reader.u8()?;
+                let old = if marker == b'K' || marker == b'O' {
+                    let old = Self::read_tuple(&mut reader)?;
+                    marker = reader.u8()?;
+                    Some(old)
+                } else {
+                    None
+                };
+                if marker != b'N' {
+                    return Err(format!("Unexpected pgoutput update marker {:?}", marker as char).into());
+                }
+                let new = Self::read_tuple(&mut reader)?;
+                let relation = self.relation(relid)?;
+                // Without an old tuple the key did not change and can be taken from the new row
+                let key = Self::key_values(relation, old.as_ref().unwrap_or(&new));
+                let change = Change::Update {
+                    table: relation.table.clone(),
+                    key,
+                    new: Self::tuple_values(relation, &new),
+                };
+                self.push_change(change)?;
+            }
+            b'D' => {
+                let relid = reader.u32()?;
+                let _old_marker = reader.u8()?;
+                let old = Self::read_tuple(&mut reader)?;
+                let relation = self.relation(relid)?;
+                let change = Change::Delete {
+                    table: relation.table.clone(),
+                    key: Self::key_values(relation, &old),
+                };
+                self.push_change(change)?;
+            }
+            b'T' => {
+                let nrels = reader.u32()?;
+                let _options = reader.u8()?;
+                let mut tables = Vec::with_capacity(nrels as usize);
+                for _ in 0..nrels {
+                    let relid = reader.u32()?;
+                    tables.push(self.relation(relid)?.table.clone());
+                }
+                self.push_change(Change::Truncate { tables })?;
+            }
+            // Type, Origin and logical Message records carry nothing to apply
+            _ => {}
+        }
+        Ok(None)
+    }
+}
+
+// Decodes wal2json (format-version 2) rows, one JSON document per change
+#[derive(Default)]
+pub struct Wal2jsonDecoder {
+    current: Option<SourceTransaction>,
+}
+
+impl Wal2jsonDecoder {
+    fn json_text(value: &Value) -> Option<String> {
+        match value {
+            Value::Null => None,
+            Value::String(s) => Some(s.clone()),
+            other => Some(other.to_string()),
+        }
+    }
+
+    fn columns(doc: &Value, field: &str) -> ColumnValues {
+        doc[field]
+            .as_array()
+            .map(|cols| {
+                cols.iter()
+                    .filter_map(|c| c["name"].as_str().map(|name| (name.to_string(), Self::json_text(&c["value"]))))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Replica identity if wal2json sent it, otherwise the primary key columns of the new row
+    fn key(doc: &Value) -> ColumnValues {
+        let identity = Self::columns(doc, "identity");
+        if !identity.is_empty() {
+            return identity;
+        }
+        let pk: Vec<&str> = doc["pk"]
+            .as_array()
+            .map(|cols| cols.iter().filter_map(|c| c["name"].as_str()).collect())
+            .unwrap_or_default();
+        Self::columns(doc, "columns")
+            .into_iter()
+            .filter(|(name, _)| pk.contains(&name.as_str()))
+            .collect()
+    }
+
+    pub fn decode(&mut self, lsn: u64, data: &str) -> Result<Option<SourceTransaction>, Box<dyn Error>> {
+        let doc: Value = serde_json::from_str(data)?;
+        let table = || TableRef {
+            schema: doc["schema"].as_str().unwrap_or("public").to_string(),
+            name: doc["table"].as_str().unwrap_or_default().to_string(),
+        };
+        let change = match doc["action"].as_str().unwrap_or_default() {
+            "B" => {
+                self.current = Some(SourceTransaction::default());
+                return Ok(None);
+            }
+            "C" => {
+                let mut tx = self.current.take().ok_or("wal2json commit without begin")?;
+                tx.end_lsn = lsn;
+                return Ok(Some(tx));
+            }
+            "I" => Change::Insert { table: table(), new: Self::columns(&doc, "columns") },
+            "U" => Change::Update { table: table(), key: Self::key(&doc), new: Self::columns(&doc, "columns") },
+            "D" => Change::Delete { table: table(), key: Self::key(&doc) },
+            "T" => Change::Truncate { tables: vec![table()] },
+            _ => return Ok(None),
+        };
+        match self.current.as_mut() {
+            Some(tx) => tx.changes.push(change),
+            None => return Err("wal2json change outside of a transaction".into()),
+        }
+        Ok(None)
+    }
+}
+
+// target table -> column name -> type, filled lazily while applying changes
+pub type ColumnTypesCache = HashMap<TableRef, HashMap<String, String>>;
+
+// Column types of the target table, used to cast the text values coming from the slot
+async fn target_column_types(
+    target_pool: &PgPool,
+    cache: &mut ColumnTypesCache,
+    table: &TableRef,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    if let Some(types) = cache.get(table) {
+        return Ok(types.clone());
+    }
+    let rows = sqlx::query(
+        "SELECT a.attname::text, format_type(a.atttypid, a.atttypmod)
+         FROM pg_attribute a
+         WHERE a.attrelid = $1::regclass AND a.attnum > 0 AND NOT a.attisdropped",
+    )
+    .bind(table.quoted())
+    .fetch_all(target_pool)
+    .await?;
+    let mut types = HashMap::new();
+    for row in rows {
+        types.insert(row.try_get::<String, _>(0)?, row.try_get::<String, _>(1)?);
+    }
+    cache.insert(table.clone(), types.clone());
+    Ok(types)
+}
+
+fn cast_param(types: &HashMap<String, String>, table: &TableRef, column: &str, index: usize) -> Result<String, Box<dyn Error>> {
+    let data_type = types
+        .get(column)
+        .ok_or_else(|| format!("Column {} does not exist in target table {}", column, table.quoted()))?;
+    Ok(format!("${}::text::{}", index, data_type))
+}
+
+// Apply one decoded change inside the target transaction. Shared with the trigger based capture.
+pub(crate) async fn apply_change(
+    tx: &mut PgTransaction<'_, Postgres>,
+    target_pool: &PgPool,
+    types_cache: &mut ColumnTypesCache,
+    change: &Change,
+) -> Result<(), Box<dyn Error>> {
+    let (statement, params): (String, Vec<&Option<String>>) = match change {
+        Change::Insert { table, new } => {
+            let types = target_column_types(target_pool, types_cache, table).await?;
+            let columns = new.iter().map(|(name, _)| quote_ident(name)).collect::<Vec<_>>().join(", ");
+            let values = new
+                .iter()
+                .enumerate()
+                .map(|(i, (name, _))| cast_param(&types, table, name, i + 1))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ");
+            (
+                format!("INSERT INTO {} ({}) VALUES ({})", table.quoted(), columns, values),
+                new.iter().map(|(_, v)| v).collect(),
+            )
+        }
+        Change::Update { table, key, new } => {
+            if key.is_empty() {
+                return Err(format!("Cannot apply UPDATE on {} without a replica identity", table.quoted()).into());
+            }
+            let types = target_column_types(target_pool, types_cache, table).await?;
+            let mut sets = Vec::new();
+            for (i, (name, _)) in new.iter().enumerate() {
+                sets.push(format!("{} = {}", quote_ident(name), cast_param(&types, table, name, i + 1)?));
+            }
+            let mut conditions = Vec::new();
+            for (i, (name, _)) in key.iter().enumerate() {
+                conditions.push(format!(
+                    "{} IS NOT DISTINCT FROM {}",
+                    quote_ident(name),
+                    cast_param(&types, table, name, new.len() + i + 1)?
+                ));
+            }
+            (
+                format!("UPDATE {} SET {} WHERE {}", table.quoted(), sets.join(", "), conditions.join(" AND ")),
+                new.iter().chain(key.iter()).map(|(_, v)| v).collect(),
+            )
+        }
+        Change::Delete { table, key } => {
+            if key.is_empty() {
+                return Err(format!("Cannot apply DELETE on {} without a replica identity", table.quoted()).into());
+            }
+            let types = target_column_types(target_pool, types_cache, table).await?;
+            let mut conditions = Vec::new();
+            for (i, (name, _)) in key.iter().enumerate() {
+                conditions.push(format!("{} IS NOT DISTINCT FROM {}", quote_ident(name), cast_param(&types, table, name, i + 1)?));
+            }
+            (
+                format!("DELETE FROM {} WHERE {}", table.quoted(), conditions.join(" AND ")),
+                key.iter().map(|(_, v)| v).collect(),
+            )
+        }
+        Change::Truncate { tables } => {
+            let names = tables.iter().map(|t| t.quoted()).collect::<Vec<_>>().join(", ");
+            (format!("TRUNCATE {}", names), Vec::new())
+        }
+    };
+
+    let mut query = sqlx::query(&statement);
+    for param in params {
+        query = query.bind(param.as_deref());
+    }
+    query.execute(&mut **tx).await?;
+    Ok(())
+}
+
+async fn apply_transaction(
+    target_pool: &PgPool,
+    types_cache: &mut ColumnTypesCache,
+    slot: &str,
+    source_tx: &SourceTransaction,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = target_pool.begin().await?;
+    for change in &source_tx.changes {
+        apply_change(&mut tx, target_pool, types_cache, change).await?;
+    }
+    sqlx::query(
+        "INSERT INTO transform.sync_cdc_checkpoint (slot_name, confirmed_lsn, updated_at)
+         VALUES ($1, $2::pg_lsn, now())
+         ON CONFLICT (slot_name) DO UPDATE SET confirmed_lsn = EXCLUDED.confirmed_lsn, updated_at = EXCLUDED.updated_at",
+    )
+    .bind(slot)
+    .bind(format_lsn(source_tx.end_lsn))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Peek the next batch of complete transactions from the slot
+async fn fetch_transactions(
+    source_pool: &PgPool,
+    cdc: &CdcConfig,
+    tables: &[&str],
+    pgoutput: &mut PgoutputDecoder,
+    wal2json: &mut Wal2jsonDecoder,
+) -> Result<Vec<SourceTransaction>, Box<dyn Error>> {
+    let mut transactions = Vec::new();
+    match cdc.plugin {
+        OutputPlugin::Pgoutput => {
+            let rows = sqlx::query(
+                "SELECT lsn::text, data FROM pg_logical_slot_peek_binary_changes($1, NULL, $2, 'proto_version', '1', 'publication_names', $3)",
+            )
+            .bind(&cdc.slot)
+            .bind(cdc.batch_changes)
+            .bind(&cdc.publication)
+            .fetch_all(source_pool)
+            .await?;
+            for row in rows {
+                let data: Vec<u8> = row.try_get("data")?;
+                if let Some(tx) = pgoutput.decode(&data)? {
+                    transactions.push(tx);
+                }
+            }
+        }
+        OutputPlugin::Wal2json => {
+            // wal2json filters with schema.table patterns, unqualified names match any schema
+            let add_tables = tables
+                .iter()
+                .map(|t| if t.contains('.') { t.to_string() } else { format!("*.{}", t) })
+                .collect::<Vec<_>>()
+                .join(",");
+            let rows = sqlx::query(
+                "SELECT lsn::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2, 'format-version', '2', 'include-pk', 'true', 'add-tables', $3)",
+            )
+            .bind(&cdc.slot)
+            .bind(cdc.batch_changes)
+            .bind(add_tables)
+            .fetch_all(source_pool)
+            .await?;
+            for row in rows {
+                let lsn: String = row.try_get("lsn")?;
+                let data: String = row.try_get("data")?;
+                if let Some(tx) = wal2json.decode(parse_lsn(&lsn)?, &data)? {
+                    transactions.push(tx);
+                }
+            }
+        }
+    }
+    Ok(transactions)
+}
+
+// Stream changes from the replication slot into the target until the process is stopped
+pub async fn run(source_pool: &PgPool, target_pool: &PgPool, config: &SyncConfig) -> Result<(), Box<dyn Error>> {
+    let cdc = &config.cdc;
+    let tables = config.table_names();
+
+    if cdc.plugin == OutputPlugin::Pgoutput {
+        ensure_publication(source_pool, cdc, &tables).await?;
+    }
+    ensure_slot(source_pool, cdc).await?;
+    ensure_checkpoint_table(target_pool).await?;
+
+    let mut confirmed_lsn = load_checkpoint(target_pool, &cdc.slot).await?;
+    info!(slot = %cdc.slot, lsn = %format_lsn(confirmed_lsn), "resuming slot");
+
+    let mut pgoutput = PgoutputDecoder::default();
+    let mut wal2json = Wal2jsonDecoder::default();
+    let mut types_cache = HashMap::new();
+
+    loop {
+        let transactions = fetch_transactions(source_pool, cdc, &tables, &mut pgoutput, &mut wal2json).await?;
+        if transactions.is_empty() {
+            tokio::time::sleep(Duration::from_millis(cdc.poll_interval_ms)).await;
+            continue;
+        }
+
+        let mut applied = 0;
+        let mut last_lsn = confirmed_lsn;
+        for tx in &transactions {
+            last_lsn = last_lsn.max(tx.end_lsn);
+            // Already applied before a restart, the slot just wasn't advanced yet
+            if tx.end_lsn <= confirmed_lsn {
+                continue;
+            }
+            apply_transaction(target_pool, &mut types_cache, &cdc.slot, tx).await?;
+            confirmed_lsn = tx.end_lsn;
+            applied += 1;
+        }
+
+        sqlx::query("SELECT pg_replication_slot_advance($1, $2::pg_lsn)")
+            .bind(&cdc.slot)
+            .bind(format_lsn(last_lsn))
+            .execute(source_pool)
+            .await?;
+        info!(transactions = applied, slot = %cdc.slot, lsn = %format_lsn(last_lsn), "applied transactions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsn_round_trip() {
+        assert_eq!(parse_lsn("0/0").unwrap(), 0);
+        assert_eq!(parse_lsn("16/B374D848").unwrap(), 0x16_B374_D848);
+        assert_eq!(format_lsn(0x16_B374_D848), "16/B374D848");
+        assert_eq!(parse_lsn(&format_lsn(u64::MAX)).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn invalid_lsn() {
+        assert!(parse_lsn("").is_err());
+        assert!(parse_lsn("16B374D848").is_err());
+        assert!(parse_lsn("16/").is_err());
+        assert!(parse_lsn("G/1").is_err());
+        assert!(parse_lsn("1/FFFFFFFFFFFFFFFFF").is_err());
+    }
+
+    // pgoutput message builders
+    fn relation(relid: u32, columns: &[(&str, bool)]) -> Vec<u8> {
+        let mut m = vec![b'R'];
+        m.extend(relid.to_be_bytes());
+        m.extend(b"public\0items\0");
+        m.push(b'd');
+        m.extend((columns.len() as i16).to_be_bytes());
+        for (name, key) in columns {
+            m.push(*key as u8);
+            m.extend(name.as_bytes());
+            m.push(0);
+            m.extend(25u32.to_be_bytes());
+            m.extend((-1i32).to_be_bytes());
+        }
+        m
+    }
+
+    fn tuple(values: &[Option<Option<&str>>]) -> Vec<u8> {
+        let mut m = (values.len() as i16).to_be_bytes().to_vec();
+        for value in values {
+            match value {
+                Some(Some(text)) => {
+                    m.push(b't');
+                    m.extend((text.len() as i32).to_be_bytes());
+                    m.extend(text.as_bytes());
+                }
+                Some(None) => m.push(b'n'),
+                None => m.push(b'u'),
+            }
+        }
+        m
+    }
+
+    fn commit(end_lsn: u64) -> Vec<u8> {
+        let mut m = vec![b'C', 0];
+        m.extend(1u64.to_be_bytes());
+        m.extend(end_lsn.to_be_bytes());
+        m.extend(0i64.to_be_bytes());
+        m
+    }
+
+    fn pgoutput_decoder() -> PgoutputDecoder {
+        let mut decoder = PgoutputDecoder::default();
+        assert!(decoder.decode(&relation(7, &[("id", true), ("note", false)])).unwrap().is_none());
+        decoder
+    }
+
+    #[test]
+    fn pgoutput_transaction() {
+        let mut decoder = pgoutput_decoder();
+        decoder.decode(b"B").unwrap();
+        let mut insert = vec![b'I'];
+        insert.extend(7u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(tuple(&[Some(Some("1")), Some(None)]));
+        decoder.decode(&insert).unwrap();
+        // unchanged TOAST value and no old tuple: the key comes from the new row
+        let mut update = vec![b'U'];
+        update.extend(7u32.to_be_bytes());
+        update.push(b'N');
+        update.extend(tuple(&[Some(Some("1")), None]));
+        decoder.decode(&update).unwrap();
+        let mut delete = vec![b'D'];
+        delete.extend(7u32.to_be_bytes());
+        delete.push(b'K');
+        delete.extend(tuple(&[Some(Some("1")), Some(None)]));
+        decoder.decode(&delete).unwrap();
+        let tx = decoder.decode(&commit(42)).unwrap().unwrap();
+        assert_eq!(tx.end_lsn, 42);
+        let table = TableRef::parse("items");
+        match &tx.changes[..] {
+            [Change::Insert { table: t1, new }, Change::Update { table: t2, key, new: updated }, Change::Delete { table: t3, key: deleted }] => {
+                assert!(t1 == &table && t2 == &table && t3 == &table);
+                assert_eq!(new, &vec![("id".to_string(), Some("1".to_string())), ("note".to_string(), None)]);
+                assert_eq!(key, &vec![("id".to_string(), Some("1".to_string()))]);
+                assert_eq!(updated, &vec![("id".to_string(), Some("1".to_string()))]);
+                assert_eq!(deleted, key);
+            }
+            other => panic!("unexpected changes {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pgoutput_truncated_messages() {
+        let mut decoder = pgoutput_decoder();
+        decoder.decode(b"B").unwrap();
+        let mut insert = vec![b'I'];
+        insert.extend(7u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(tuple(&[Some(Some("12345")), Some(None)]));
+        for len in 1..insert.len() {
+            assert!(decoder.decode(&insert[..len]).is_err(), "prefix of {} bytes", len);
+        }
+        assert!(decoder.decode(&[]).is_err());
+        assert!(decoder.decode(&commit(1)[..10]).is_err());
+        assert!(PgoutputDecoder::default().decode(b"R\0\0\0\x07public").is_err());
+    }
+
+    #[test]
+    fn pgoutput_negative_length() {
+        let mut decoder = pgoutput_decoder();
+        decoder.decode(b"B").unwrap();
+        let mut insert = vec![b'I'];
+        insert.extend(7u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(2i16.to_be_bytes());
+        insert.push(b't');
+        insert.extend((-1i32).to_be_bytes());
+        assert!(decoder.decode(&insert).is_err());
+    }
+
+    #[test]
+    fn pgoutput_errors() {
+        let mut decoder = pgoutput_decoder();
+        let mut insert = vec![b'I'];
+        insert.extend(7u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(tuple(&[Some(Some("1")), Some(None)]));
+        // outside of a transaction, unknown relation, commit without begin
+        assert!(decoder.decode(&insert).is_err());
+        decoder.decode(b"B").unwrap();
+        insert[1..5].copy_from_slice(&8u32.to_be_bytes());
+        assert!(decoder.decode(&insert).is_err());
+        assert!(PgoutputDecoder::default().decode(&commit(1)).is_err());
+        // Origin and Type messages are skipped
+        assert!(decoder.decode(b"O").unwrap().is_none());
+    }
+
+    #[test]
+    fn wal2json_transaction() {
+        let mut decoder = Wal2jsonDecoder::default();
+        decoder.decode(1, r#"{"action":"B"}"#).unwrap();
+        decoder
+            .decode(2, r#"{"action":"I","schema":"s","table":"t","columns":[{"name":"id","value":1},{"name":"n","value":null}],"pk":[{"name":"id"}]}"#)
+            .unwrap();
+        decoder
+            .decode(3, r#"{"action":"U","schema":"s","table":"t","columns":[{"name":"id","value":2},{"name":"n","value":"x"}],"pk":[{"name":"id"}]}"#)
+            .unwrap();
+        decoder.decode(4, r#"{"action":"D","schema":"s","table":"t","identity":[{"name":"id","value":3}]}"#).unwrap();
+        decoder.decode(5, r#"{"action":"M","prefix":"p","content":"c"}"#).unwrap();
+        let tx = decoder.decode(9, r#"{"action":"C"}"#).unwrap().unwrap();
+        assert_eq!(tx.end_lsn, 9);
+        match &tx.changes[..] {
+            [Change::Insert { new, .. }, Change::Update { key, .. }, Change::Delete { table, key: deleted }] => {
+                assert_eq!(new, &vec![("id".to_string(), Some("1".to_string())), ("n".to_string(), None)]);
+                assert_eq!(key, &vec![("id".to_string(), Some("2".to_string()))]);
+                assert_eq!(table, &TableRef { schema: "s".to_string(), name: "t".to_string() });
+                assert_eq!(deleted, &vec![("id".to_string(), Some("3".to_string()))]);
+            }
+            other => panic!("unexpected changes {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wal2json_errors() {
+        let mut decoder = Wal2jsonDecoder::default();
+        assert!(decoder.decode(1, r#"{"action":"I","table":"t","columns":[]}"#).is_err());
+        assert!(decoder.decode(1, r#"{"action":"C"}"#).is_err());
+        assert!(decoder.decode(1, r#"{"action":"B""#).is_err());
+    }
+}
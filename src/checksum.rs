@@ -0,0 +1,345 @@
+// `verify --checksum`: compares row contents between source and target by primary key range.
+// Each table is cut into chunks of `--chunk-rows` source rows in key order, and both sides hash
+// every chunk order-stably (`md5(string_agg(ROW(columns)::text ORDER BY key))` over the copied
+// columns). Chunks that differ are bisected at the median key of the side with more rows until
+// a range holds at most `--leaf-rows` rows; adjacent differing ranges are reported merged.
+// Each side is read in one REPEATABLE READ transaction with fixed TimeZone/DateStyle settings so
+// values render the same, keys are assumed to sort the same on both servers (collations).
+// Tables with transforms or masking, or without a primary key, are skipped.
+use crate::catalog::{column_names, primary_key_columns, selected_columns};
+use crate::config::TableConfig;
+use crate::filter::where_clause;
+use crate::shard;
+use crate::sql::{column_list, quote_ident, quote_literal};
+use crate::verify::{self, VerifyOptions};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool, Postgres, Row, Transaction};
+use std::error::Error;
+
+// Primary key columns of a table with their types, for typed key literals
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub columns: Vec<String>,
+    types: Vec<String>,
+}
+
+impl Key {
+    // None if the table has no primary key
+    pub async fn load(pool: &PgPool, table_name: &str) -> Result<Option<Key>, Box<dyn Error>> {
+        let columns = primary_key_columns(pool, table_name).await?;
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let types: Vec<String> = sqlx::query_scalar(
+            "SELECT format_type(a.atttypid, a.atttypmod) FROM unnest($2::text[]) WITH ORDINALITY AS k(name, position)
+             JOIN pg_attribute a ON a.attrelid = $1::regclass AND a.attname = k.name
+             ORDER BY k.position",
+        )
+        .bind(table_name)
+        .bind(&columns)
+        .fetch_all(pool)
+        .await?;
+        Ok(Some(Key { columns, types }))
+    }
+
+    // `("a", "b")`, compared as a row value
+    fn row(&self) -> String {
+        format!("({})", column_list(&self.columns))
+    }
+
+    // The key as a text array, the representation of range bounds
+    fn text_array(&self) -> String {
+        let values: Vec<String> = self.columns.iter().map(|c| format!("{}::text", quote_ident(c))).collect();
+        format!("ARRAY[{}]", values.join(", "))
+    }
+
+    fn literal(&self, values: &[String]) -> String {
+        let values: Vec<String> = values.iter().zip(&self.types).map(|(v, t)| format!("{}::{}", quote_literal(v), t)).collect();
+        format!("({})", values.join(", "))
+    }
+}
+
+// Keys from `lower` (inclusive) to `upper` (exclusive), None is unbounded
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyRange {
+    pub lower: Option<Vec<String>>,
+    pub upper: Option<Vec<String>>,
+}
+
+impl KeyRange {
+    pub fn predicate(&self, key: &Key) -> String {
+        let mut conditions = Vec::new();
+        if let Some(lower) = &self.lower {
+            conditions.push(format!("{} >= {}", key.row(), key.literal(lower)));
+        }
+        if let Some(upper) = &self.upper {
+            conditions.push(format!("{} < {}", key.row(), key.literal(upper)));
+        }
+        if conditions.is_empty() {
+            return "TRUE".to_string();
+        }
+        conditions.join(" AND ")
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeDiff {
+    #[serde(flatten)]
+    pub range: KeyRange,
+    pub predicate: String,
+    pub source_rows: i64,
+    pub target_rows: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableChecksum {
+    pub table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub target: String,
+    // why the table wasn't compared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    pub chunks: usize,
+    pub differing_chunks: usize,
+    pub differences: Vec<RangeDiff>,
+}
+
+impl TableChecksum {
+    pub fn matches(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+// Row count and content hash of one range on one side
+#[derive(Debug, PartialEq)]
+struct Digest {
+    rows: i64,
+    hash: Option<String>,
+}
+
+// What is hashed on one side: the table, its copied columns and the conditions selecting the synced rows
+struct Side<'a> {
+    table_name: &'a str,
+    key: &'a Key,
+    columns: &'a [String],
+    conditions: Vec<String>,
+}
+
+impl Side<'_> {
+    fn where_clause(&self, range: &KeyRange) -> String {
+        let mut conditions = self.conditions.clone();
+        conditions.push(range.predicate(self.key));
+        where_clause(&conditions)
+    }
+
+    async fn digest(&self, conn: &mut PgConnection, range: &KeyRange) -> Result<Digest, Box<dyn Error>> {
+        let query = format!(
+            "SELECT count(*), md5(string_agg(ROW({})::text, E'\\n' ORDER BY {})) FROM {} {}",
+            column_list(self.columns),
+            column_list(&self.key.columns),
+            self.table_name,
+            self.where_clause(range)
+        );
+        let row = sqlx::query(&query).fetch_one(conn).await?;
+        Ok(Digest { rows: row.try_get(0)?, hash: row.try_get(1)? })
+    }
+
+    // Key of the row `offset` rows into the range
+    async fn key_at(&self, conn: &mut PgConnection, range: &KeyRange, offset: i64) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        let query = format!(
+            "SELECT {} FROM {} {} ORDER BY {} OFFSET $1 LIMIT 1",
+            self.key.text_array(),
+            self.table_name,
+            self.where_clause(range),
+            column_list(&self.key.columns)
+        );
+        Ok(sqlx::query_scalar(&query).bind(offset).fetch_optional(conn).await?)
+    }
+
+    // Chunks of `chunk_rows` rows in key order, the first and last open ended
+    async fn chunks(&self, conn: &mut PgConnection, chunk_rows: i64) -> Result<Vec<KeyRange>, Box<dyn Error>> {
+        let query = format!(
+            "SELECT key FROM (SELECT {} AS key, row_number() OVER (ORDER BY {}) AS n FROM {} {}) s
+             WHERE n > 1 AND (n - 1) % $1 = 0 ORDER BY n",
+            self.key.text_array(),
+            column_list(&self.key.columns),
+            self.table_name,
+            where_clause(&self.conditions)
+        );
+        let bounds: Vec<Vec<String>> = sqlx::query_scalar(&query).bind(chunk_rows).fetch_all(conn).await?;
+        let mut chunks = Vec::with_capacity(bounds.len() + 1);
+        let mut lower = None;
+        for bound in bounds {
+            chunks.push(KeyRange { lower, upper: Some(bound.clone()) });
+            lower = Some(bound);
+        }
+        chunks.push(KeyRange { lower, upper: None });
+        Ok(chunks)
+    }
+}
+
+// Snapshot and rendering settings shared by both sides
+async fn begin_read(pool: &PgPool) -> Result<Transaction<'static, Postgres>, Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY").execute(&mut *tx).await?;
+    for setting in ["TimeZone = 'UTC'", "DateStyle = 'ISO, YMD'", "IntervalStyle = 'postgres'", "extra_float_digits = 1"] {
+        sqlx::query(&format!("SET LOCAL {}", setting)).execute(&mut *tx).await?;
+    }
+    Ok(tx)
+}
+
+// Bisects the differing chunks into ranges of at most `leaf_rows` rows, in key order
+async fn differing_ranges(
+    source: &Side<'_>,
+    source_conn: &mut PgConnection,
+    target: &Side<'_>,
+    target_conn: &mut PgConnection,
+    mut pending: Vec<(KeyRange, Digest, Digest)>,
+    leaf_rows: i64,
+) -> Result<Vec<RangeDiff>, Box<dyn Error>> {
+    // Popped from the end, so ranges are kept in reverse key order
+    pending.reverse();
+    let mut differences: Vec<RangeDiff> = Vec::new();
+    while let Some((range, source_digest, target_digest)) = pending.pop() {
+        let rows = source_digest.rows.max(target_digest.rows);
+        let median = if rows <= leaf_rows {
+            None
+        } else if source_digest.rows >= target_digest.rows {
+            source.key_at(source_conn, &range, rows / 2).await?
+        } else {
+            target.key_at(target_conn, &range, rows / 2).await?
+        };
+        let Some(median) = median.filter(|median| range.lower.as_ref() != Some(median)) else {
+            // Small enough, merged with the previous range if they touch
+            match differences.last_mut() {
+                Some(last) if last.range.upper == range.lower => {
+                    last.range.upper = range.upper;
+                    last.predicate = last.range.predicate(source.key);
+                    last.source_rows += source_digest.rows;
+                    last.target_rows += target_digest.rows;
+                }
+                _ => differences.push(RangeDiff {
+                    predicate: range.predicate(source.key),
+                    range,
+                    source_rows: source_digest.rows,
+                    target_rows: target_digest.rows,
+                }),
+            }
+            continue;
+        };
+        let halves = [
+            KeyRange { lower: Some(median.clone()), upper: range.upper },
+            KeyRange { lower: range.lower, upper: Some(median) },
+        ];
+        for half in halves {
+            let source_digest = source.digest(source_conn, &half).await?;
+            let target_digest = target.digest(target_conn, &half).await?;
+            if source_digest != target_digest {
+                pending.push((half, source_digest, target_digest));
+            }
+        }
+    }
+    Ok(differences)
+}
+
+// Checksums of one table against one target, with the ranges that differ
+pub async fn checksum_table(
+    source_pool: &PgPool,
+    source_id: Option<&str>,
+    target_name: &str,
+    target_pool: &PgPool,
+    table: &TableConfig,
+    options: &VerifyOptions,
+) -> Result<TableChecksum, Box<dyn Error>> {
+    let mut result = TableChecksum {
+        table: table.name.clone(),
+        source: source_id.map(str::to_string),
+        target: target_name.to_string(),
+        skipped: None,
+        chunks: 0,
+        differing_chunks: 0,
+        differences: Vec::new(),
+    };
+    if table.rewrites_rows() {
+        result.skipped = Some("transforms or masking change the copied values".to_string());
+        return Ok(result);
+    }
+    let key = match Key::load(source_pool, &table.name).await? {
+        None => {
+            result.skipped = Some("no primary key".to_string());
+            return Ok(result);
+        }
+        Some(key) => key,
+    };
+    let columns = match selected_columns(source_pool, table).await? {
+        Some(columns) => columns,
+        None => column_names(source_pool, &table.name).await?,
+    };
+    let (_, window) = verify::window(source_pool, table, options).await?;
+    let mut source_conditions = window.clone();
+    source_conditions.extend(table.filter.clone());
+    let mut target_conditions = window;
+    target_conditions.extend(source_id.map(shard::source_condition));
+    let source = Side { table_name: &table.name, key: &key, columns: &columns, conditions: source_conditions };
+    let target = Side { table_name: &table.name, key: &key, columns: &columns, conditions: target_conditions };
+
+    let mut source_tx = begin_read(source_pool).await?;
+    let mut target_tx = begin_read(target_pool).await?;
+    let chunks = source.chunks(&mut source_tx, options.chunk_rows).await?;
+    result.chunks = chunks.len();
+    let mut differing = Vec::new();
+    for chunk in chunks {
+        let source_digest = source.digest(&mut source_tx, &chunk).await?;
+        let target_digest = target.digest(&mut target_tx, &chunk).await?;
+        if source_digest != target_digest {
+            differing.push((chunk, source_digest, target_digest));
+        }
+    }
+    result.differing_chunks = differing.len();
+    result.differences = differing_ranges(&source, &mut source_tx, &target, &mut target_tx, differing, options.leaf_rows).await?;
+    source_tx.rollback().await?;
+    target_tx.rollback().await?;
+    Ok(result)
+}
+
+// Checksums of every table for one source against every target
+pub async fn checksum_tables(
+    source_pool: &PgPool,
+    source_id: Option<&str>,
+    targets: &[(&str, &PgPool)],
+    tables: &[TableConfig],
+    options: &VerifyOptions,
+) -> Result<Vec<TableChecksum>, Box<dyn Error>> {
+    let mut results = Vec::new();
+    for table in tables {
+        for (target_name, target_pool) in targets {
+            results.push(checksum_table(source_pool, source_id, target_name, target_pool, table, options).await?);
+        }
+    }
+    Ok(results)
+}
+
+pub fn print_report(results: &[TableChecksum], json: bool) -> Result<(), Box<dyn Error>> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+    for result in results {
+        let name = shard::state_key(&result.table, result.source.as_deref());
+        match &result.skipped {
+            Some(reason) => println!("{} on {}: skipped, {}", name, result.target, reason),
+            None if result.matches() => println!("{} on {}: {} chunks match", name, result.target, result.chunks),
+            None => {
+                println!("{} on {}: {} of {} chunks differ in {} ranges", name, result.target, result.differing_chunks, result.chunks, result.differences.len());
+                for difference in &result.differences {
+                    println!(
+                        "  {} source rows, {} target rows: {}",
+                        difference.source_rows, difference.target_rows, difference.predicate
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,473 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+
+// Job configuration, read from the JSON file pointed to by SYNC_CONFIG, e.g.
+// {
+//   "tables": [
+//     { "name": "table1", "lookback": "15 minutes", "transforms": { "email": ["trim", "lowercase"] } },
+//     { "name": "table2", "filter": "tenant_id = 42", "backfill": { "split_by": "primary_key", "chunk_size": 1000000 } },
+//     { "name": "table3", "parallel": { "slices": 8, "split_by": "histogram" } }
+//   ],
+//   "targets": [{ "name": "analytics", "url_env": "POSTGRES_URL_ANALYTICS" }, { "name": "reporting", "url_env": "POSTGRES_URL_REPORTING" }],
+//   "sources": [{ "id": "shard1", "url_env": "POSTGRES_URL_SHARD1" }, { "id": "shard2", "url_env": "POSTGRES_URL_SHARD2" }],
+//   "consistent_snapshot": true,
+//   "session": { "source": { "statement_timeout": "30min", "read_only": true }, "target": { "lock_timeout": "30s" } },
+//   "throttle": { "bytes_per_sec": 10000000, "max_replication_lag_secs": 30 },
+//   "spool": { "directory": "/var/tmp/sync", "compression": "zstd" },
+//   "retry": { "max_attempts": 5, "max_elapsed_secs": 900 },
+//   "copy_buffer_bytes": 8388608,
+//   "copy_format": "binary",
+//   "cdc": { "publication": "data_sync", "slot": "data_sync", "plugin": "pgoutput" },
+//   "trigger": { "schema": "sync_audit" },
+//   "exclude_columns": ["_airbyte*"],
+//   "logging": { "format": "json", "level": "info" }
+// }
+// Without SYNC_CONFIG the defaults below are used (same single table as before).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub tables: Vec<TableConfig>,
+    // target databases every table is copied to from one source read, POSTGRES_URL_TARGET if empty
+    pub targets: Vec<TargetConfig>,
+    // source databases consolidated into the targets by `_source_id`, see shard.rs;
+    // POSTGRES_URL_SOURCE without a source id if empty
+    pub sources: Vec<SourceConfig>,
+    // settings applied to every source and target connection, see session.rs
+    pub session: SessionConfig,
+    // read all tables of a run from one exported source snapshot
+    pub consistent_snapshot: bool,
+    // COPY format for all tables, can be overridden per table
+    pub copy_format: CopyFormat,
+    pub cdc: CdcConfig,
+    pub trigger: TriggerConfig,
+    // extract to local compressed files before loading, see spool.rs
+    pub spool: Option<SpoolConfig>,
+    // bytes buffered between reading the source and writing the targets, see pipe.rs
+    pub copy_buffer_bytes: usize,
+    // retries of a table or backfill range after transient errors, see retry.rs
+    pub retry: RetryConfig,
+    // rate limits and source load thresholds for all tables, fields can be overridden per table
+    pub throttle: ThrottleConfig,
+    // columns left out of every table without exclude_columns of its own, by default none in the
+    // COPY sync and the Airbyte metadata columns in parse_date_jsonb, see with_column_excludes
+    pub exclude_columns: Option<Vec<String>>,
+    // log format and level of both binaries, see logging.rs
+    pub logging: LoggingConfig,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            tables: vec![TableConfig::new("table1")],
+            targets: Vec::new(),
+            sources: Vec::new(),
+            session: SessionConfig::default(),
+            consistent_snapshot: false,
+            copy_format: CopyFormat::Csv,
+            cdc: CdcConfig::default(),
+            trigger: TriggerConfig::default(),
+            spool: None,
+            copy_buffer_bytes: 8 * 1024 * 1024,
+            retry: RetryConfig::default(),
+            throttle: ThrottleConfig::default(),
+            exclude_columns: None,
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl SyncConfig {
+    pub fn from_env() -> Result<SyncConfig, Box<dyn Error>> {
+        match env::var("SYNC_CONFIG") {
+            Ok(path) => SyncConfig::from_file(&path),
+            Err(_) => Ok(SyncConfig::default()),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<SyncConfig, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+        let config: SyncConfig = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse config {}: {}", path, e))?;
+        Ok(config)
+    }
+
+    // Gives every table without exclude_columns of its own the global ones, `default` if there are
+    // none configured. An explicit per table list, even an empty one, replaces the global list.
+    pub fn with_column_excludes(mut self, default: &[&str]) -> Self {
+        let global = self.exclude_columns.take().unwrap_or_else(|| default.iter().map(|c| c.to_string()).collect());
+        for table in &mut self.tables {
+            table.exclude_columns.get_or_insert_with(|| global.clone());
+        }
+        self.exclude_columns = Some(global);
+        self
+    }
+
+    pub fn table_names(&self) -> Vec<&str> {
+        self.tables.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableConfig> {
+        self.tables.iter().find(|t| t.name == name)
+    }
+
+    // The configured table, or its defaults with the global column excludes if it isn't configured
+    pub fn table_or_default(&self, name: &str) -> TableConfig {
+        self.table(name).cloned().unwrap_or_else(|| {
+            let mut table = TableConfig::new(name);
+            table.exclude_columns.clone_from(&self.exclude_columns);
+            table
+        })
+    }
+
+    pub fn throttle_for(&self, table: &TableConfig) -> ThrottleConfig {
+        let global = &self.throttle;
+        match &table.throttle {
+            Some(own) => ThrottleConfig {
+                bytes_per_sec: own.bytes_per_sec.or(global.bytes_per_sec),
+                rows_per_sec: own.rows_per_sec.or(global.rows_per_sec),
+                max_replication_lag_secs: own.max_replication_lag_secs.or(global.max_replication_lag_secs),
+                max_active_connections: own.max_active_connections.or(global.max_active_connections),
+                check_interval_ms: own.check_interval_ms.or(global.check_interval_ms),
+            },
+            None => global.clone(),
+        }
+    }
+
+    // Most slices any table is copied in, pools need a connection per slice
+    pub fn max_slices(&self) -> usize {
+        self.tables.iter().filter_map(|t| t.parallel.as_ref()).map(|p| p.slices).max().unwrap_or(1)
+    }
+
+    pub fn copy_format_for(&self, table: &TableConfig) -> CopyFormat {
+        // the transformation pipeline only parses CSV, rows/sec counts CSV lines
+        if table.rewrites_rows() || self.throttle_for(table).rows_per_sec.is_some() {
+            return CopyFormat::Csv;
+        }
+        table.copy_format.unwrap_or(self.copy_format)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TableConfig {
+    pub name: String,
+    // Postgres interval re-scanned before the created_at/updated_at watermark, e.g. "15 minutes",
+    // so rows from transactions that committed after the previous run aren't skipped
+    #[serde(default)]
+    pub lookback: Option<String>,
+    // Incremental cursor column (timestamp, timestamptz, date or integer) instead of created_at/updated_at
+    #[serde(default)]
+    pub cursor_column: Option<String>,
+    // Split the initial load into ranges that are copied and checkpointed one by one
+    #[serde(default)]
+    pub backfill: Option<BackfillConfig>,
+    // Copy the table in N slices concurrently (not used for backfill ranges)
+    #[serde(default)]
+    pub parallel: Option<ParallelConfig>,
+    #[serde(default)]
+    pub copy_format: Option<CopyFormat>,
+    // SQL condition restricting which rows are copied, e.g. "tenant_id = 42", ANDed with the cursor predicate
+    #[serde(default)]
+    pub filter: Option<String>,
+    // Columns to copy (all by default) and columns to leave out, a trailing * matches a prefix,
+    // e.g. "exclude_columns": ["payload", "_internal*"]
+    #[serde(default)]
+    pub include_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub throttle: Option<ThrottleConfig>,
+    // Transforms applied to column values between COPY OUT and COPY IN, in order, e.g.
+    // { "email": ["trim", "lowercase"], "status": [{ "null_if": "N/A" }] }. Forces the CSV format.
+    #[serde(default)]
+    pub transforms: BTreeMap<String, Vec<TransformConfig>>,
+    // Masking policy per column for non-production targets, applied after the transforms,
+    // e.g. { "email": "hash", "phone": "fake", "notes": "null", "birth_date": { "shift_date": 30 } }
+    #[serde(default)]
+    pub masking: BTreeMap<String, MaskRule>,
+}
+
+impl TableConfig {
+    pub fn new(name: &str) -> Self {
+        TableConfig {
+            name: name.to_string(),
+            lookback: None,
+            cursor_column: None,
+            backfill: None,
+            parallel: None,
+            copy_format: None,
+            filter: None,
+            throttle: None,
+            include_columns: None,
+            exclude_columns: None,
+            transforms: BTreeMap::new(),
+            masking: BTreeMap::new(),
+        }
+    }
+
+    pub fn selects_column(&self, column: &str) -> bool {
+        let included = match &self.include_columns {
+            Some(include) => include.iter().any(|pattern| column_matches(pattern, column)),
+            None => true,
+        };
+        included && !self.exclude_columns.iter().flatten().any(|pattern| column_matches(pattern, column))
+    }
+
+    // Rows have to go through the transformation pipeline instead of being streamed as-is
+    pub fn rewrites_rows(&self) -> bool {
+        !self.transforms.is_empty() || !self.masking.is_empty()
+    }
+}
+
+fn column_matches(pattern: &str, column: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => column.starts_with(prefix),
+        None => pattern == column,
+    }
+}
+
+// Keyed rules (hash, fake, shift_date) use the secret in SYNC_MASKING_KEY, see masking.rs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskRule {
+    Null,
+    Fixed(String),
+    // deterministic keyed hash, equal values stay equal so joins still work; integers and uuids keep their type
+    Hash,
+    // random-looking letters and digits in place of the original ones, other characters kept
+    Fake,
+    // keep the first N characters
+    Truncate(usize),
+    // move dates/timestamps by a deterministic number of days within +-N
+    ShiftDate(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformConfig {
+    Trim,
+    Lowercase,
+    Uppercase,
+    EmptyToNull,
+    // NULL when the value equals the given text
+    NullIf(String),
+    // the given text instead of NULL
+    Default(String),
+    // the value of another column of the same row
+    CopyFrom(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyFormat {
+    #[default]
+    Csv,
+    // used only when the source and target column types match, see copy_format.rs
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitBy {
+    // ranges of a single column integer primary key, other keys fall back to ctid
+    #[default]
+    PrimaryKey,
+    // ranges of heap pages
+    Ctid,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct BackfillConfig {
+    pub split_by: SplitBy,
+    // key values (primary_key) or pages (ctid) per range, defaults to 1_000_000 keys / 10_000 pages
+    pub chunk_size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SliceBy {
+    // ranges of a single column primary key cut at the pg_stats histogram bounds, hash without them
+    #[default]
+    Histogram,
+    // ranges of heap pages
+    Ctid,
+    // hash of the primary key modulo the number of slices
+    Hash,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParallelConfig {
+    pub slices: usize,
+    #[serde(default)]
+    pub split_by: SliceBy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpoolConfig {
+    pub directory: String,
+    pub compression: SpoolCompression,
+    // keep the files after the run, e.g. to load them somewhere else
+    pub keep: bool,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        SpoolConfig {
+            directory: "spool".to_string(),
+            compression: SpoolCompression::Zstd,
+            keep: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolCompression {
+    #[default]
+    Zstd,
+    Gzip,
+}
+
+// A target database, the connection string is read from the `url_env` environment variable
+#[derive(Debug, Clone, Deserialize)]
+pub struct TargetConfig {
+    pub name: String,
+    pub url_env: String,
+}
+
+// A source database of a fan-in job, its rows are loaded with `id` as their `_source_id`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SourceConfig {
+    pub id: String,
+    pub url_env: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub source: SessionSettings,
+    pub target: SessionSettings,
+}
+
+// Timeouts take Postgres units ("30s", "15min"), a bare number is milliseconds
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub statement_timeout: Option<String>,
+    pub lock_timeout: Option<String>,
+    pub idle_in_transaction_session_timeout: Option<String>,
+    // postgres_data_sync if not set
+    pub application_name: Option<String>,
+    // default_transaction_read_only, for the COPY sync only: cdc and trigger capture write to the source
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // attempts in total, 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // no new attempt is started once this much time has passed since the first one
+    pub max_elapsed_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 60_000,
+            max_elapsed_secs: 900,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // one JSON object per event, with the fields of the enclosing spans
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    // tracing filter directive, e.g. "debug" or "info,postgres_data_sync=debug"; RUST_LOG takes
+    // precedence, "info" if neither is set
+    pub level: Option<String>,
+}
+
+// Limits for the COPY stream, see throttle.rs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    pub bytes_per_sec: Option<u64>,
+    pub rows_per_sec: Option<u64>,
+    // pause while a standby of the source replays more than this many seconds behind
+    pub max_replication_lag_secs: Option<f64>,
+    // pause while the source has more active client connections than this
+    pub max_active_connections: Option<i64>,
+    // how often the source is checked during a copy and while paused, 5s by default
+    pub check_interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputPlugin {
+    Pgoutput,
+    Wal2json,
+}
+
+// Logical replication settings for the `cdc` mode
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CdcConfig {
+    pub publication: String,
+    pub slot: String,
+    pub plugin: OutputPlugin,
+    // max number of decoded messages fetched from the slot per round trip
+    pub batch_changes: i32,
+    // how long to wait before polling the slot again once it is drained
+    pub poll_interval_ms: u64,
+}
+
+impl Default for CdcConfig {
+    fn default() -> Self {
+        CdcConfig {
+            publication: "postgres_data_sync".to_string(),
+            slot: "postgres_data_sync".to_string(),
+            plugin: OutputPlugin::Pgoutput,
+            batch_changes: 10_000,
+            poll_interval_ms: 1_000,
+        }
+    }
+}
+
+// Trigger based capture for sources where replication slots are not available
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TriggerConfig {
+    // schema on the source holding the changelog table and the trigger functions
+    pub schema: String,
+    // changelog rows applied per target transaction
+    pub batch_size: i64,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        TriggerConfig {
+            schema: "sync_audit".to_string(),
+            batch_size: 10_000,
+        }
+    }
+}
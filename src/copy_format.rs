@@ -0,0 +1,106 @@
+// COPY wire format between source and target. Binary skips text encoding/decoding and has no
+// NULL vs empty string or embedded newline ambiguities, but only works when both sides have
+// the same columns in the same order with the same types, so it's checked per table first. When the
+// COPY names its columns (column selection, `_source_id` on consolidated targets) only the named
+// columns have to match, by name.
+use crate::config::CopyFormat;
+use sqlx::{PgPool, Row};
+use std::error::Error;
+use tracing::warn;
+
+impl CopyFormat {
+    pub fn copy_options(&self) -> &'static str {
+        match self {
+            CopyFormat::Csv => "WITH CSV HEADER",
+            CopyFormat::Binary => "WITH (FORMAT binary)",
+        }
+    }
+}
+
+struct ColumnType {
+    name: String,
+    data_type: String,
+    // user defined types (and arrays of them) have different OIDs on each side,
+    // and binary arrays/composites carry the element OIDs
+    user_defined: bool,
+}
+
+async fn column_types(pool: &PgPool, table_name: &str) -> Result<Vec<ColumnType>, Box<dyn Error>> {
+    let rows = sqlx::query(
+        "SELECT a.attname::text AS name,
+                format_type(a.atttypid, NULL) AS data_type,
+                a.atttypid::bigint >= 16384 OR t.typelem::bigint >= 16384 AS user_defined
+         FROM pg_attribute a
+         JOIN pg_type t ON t.oid = a.atttypid
+         WHERE a.attrelid = $1::regclass AND a.attnum > 0 AND NOT a.attisdropped
+         ORDER BY a.attnum",
+    )
+    .bind(table_name)
+    .fetch_all(pool)
+    .await?;
+    let mut columns = Vec::with_capacity(rows.len());
+    for row in rows {
+        columns.push(ColumnType {
+            name: row.try_get("name")?,
+            data_type: row.try_get("data_type")?,
+            user_defined: row.try_get("user_defined")?,
+        });
+    }
+    Ok(columns)
+}
+
+// Reasons the table can't be copied in binary, empty if it can. `columns` are the copied columns
+// when the COPY statements name them, all columns in table order otherwise.
+pub async fn binary_mismatches(
+    source_pool: &PgPool,
+    target_pool: &PgPool,
+    table_name: &str,
+    columns: Option<&[String]>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut source = column_types(source_pool, table_name).await?;
+    let mut target = column_types(target_pool, table_name).await?;
+    let mut mismatches = Vec::new();
+    if let Some(columns) = columns {
+        source.retain(|c| columns.contains(&c.name));
+        let mut named = Vec::with_capacity(source.len());
+        for column in &source {
+            match target.iter().position(|t| t.name == column.name) {
+                Some(i) => named.push(target.swap_remove(i)),
+                None => mismatches.push(format!("column {} is missing on the target", column.name)),
+            }
+        }
+        target = named;
+    }
+    if columns.is_none() && source.len() != target.len() {
+        mismatches.push(format!("{} source columns vs {} target columns", source.len(), target.len()));
+    }
+    for (s, t) in source.iter().zip(&target) {
+        if s.name != t.name {
+            mismatches.push(format!("column {} on the source is {} on the target", s.name, t.name));
+        } else if s.data_type != t.data_type {
+            mismatches.push(format!("column {} is {} on the source, {} on the target", s.name, s.data_type, t.data_type));
+        } else if s.user_defined {
+            mismatches.push(format!("column {} has user defined type {}", s.name, s.data_type));
+        }
+    }
+    Ok(mismatches)
+}
+
+// Binary when requested and compatible, CSV otherwise
+pub async fn choose_format(
+    source_pool: &PgPool,
+    target_pool: &PgPool,
+    table_name: &str,
+    columns: Option<&[String]>,
+    requested: CopyFormat,
+) -> Result<CopyFormat, Box<dyn Error>> {
+    if requested == CopyFormat::Csv {
+        return Ok(CopyFormat::Csv);
+    }
+    let mismatches = binary_mismatches(source_pool, target_pool, table_name, columns).await?;
+    if mismatches.is_empty() {
+        return Ok(CopyFormat::Binary);
+    }
+    warn!(table = %table_name, mismatches = %mismatches.join("; "), "can't be copied in binary, falling back to CSV");
+    Ok(CopyFormat::Csv)
+}
@@ -0,0 +1,130 @@
+// Typed incremental cursors (created_at/updated_at or a configured column) and their watermarks.
+// timestamptz watermarks are absolute instants kept in UTC, timestamp watermarks are wall-clock
+// values compared as-is without any session time zone conversion.
+use crate::sql::{quote_ident, quote_literal};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{PgPool, Postgres, Row};
+use std::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorType {
+    Timestamp,
+    TimestampTz,
+    Date,
+    Integer,
+}
+
+impl CursorType {
+    // From the type name returned by format_type()
+    pub fn from_data_type(data_type: &str) -> Option<CursorType> {
+        match data_type {
+            "timestamp without time zone" => Some(CursorType::Timestamp),
+            "timestamp with time zone" => Some(CursorType::TimestampTz),
+            "date" => Some(CursorType::Date),
+            "smallint" | "integer" | "bigint" => Some(CursorType::Integer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watermark {
+    Timestamp(NaiveDateTime),
+    TimestampTz(DateTime<Utc>),
+    Date(NaiveDate),
+    Integer(i64),
+}
+
+impl Watermark {
+    // Explicitly typed literal for statements that can't take parameters (COPY (...) TO STDOUT)
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            Watermark::Timestamp(v) => format!("{}::timestamp", quote_literal(&v.format("%Y-%m-%d %H:%M:%S%.6f").to_string())),
+            Watermark::TimestampTz(v) => format!("{}::timestamptz", quote_literal(&v.to_rfc3339_opts(SecondsFormat::Micros, false))),
+            Watermark::Date(v) => format!("{}::date", quote_literal(&v.format("%Y-%m-%d").to_string())),
+            Watermark::Integer(v) => format!("{}::bigint", v),
+        }
+    }
+
+    // Lower bound for the cursor predicate, moved back by the lookback interval. An interval can't
+    // be taken off an integer cursor, a lookback on one is a configuration error.
+    pub fn lower_bound_sql(&self, lookback: Option<&str>) -> Result<String, Box<dyn Error>> {
+        Ok(match (self, lookback) {
+            (_, None) => self.to_sql_literal(),
+            (Watermark::Integer(_), Some(lookback)) => {
+                return Err(format!("lookback {} needs a timestamp or date cursor, the cursor is an integer", quote_literal(lookback)).into())
+            }
+            (Watermark::Date(_), Some(lookback)) => format!("({} - {}::interval)::date", self.to_sql_literal(), quote_literal(lookback)),
+            (_, Some(lookback)) => format!("({} - {}::interval)", self.to_sql_literal(), quote_literal(lookback)),
+        })
+    }
+
+    pub fn bind<'q>(&self, query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+        match self {
+            Watermark::Timestamp(v) => query.bind(*v),
+            Watermark::TimestampTz(v) => query.bind(*v),
+            Watermark::Date(v) => query.bind(*v),
+            Watermark::Integer(v) => query.bind(*v),
+        }
+    }
+}
+
+// Cursor type of a column, None if it doesn't exist or can't be used as a cursor
+pub async fn column_type(pool: &PgPool, table_name: &str, column: &str) -> Result<Option<CursorType>, Box<dyn Error>> {
+    let data_type: Option<String> = sqlx::query_scalar(
+        "SELECT format_type(atttypid, NULL) FROM pg_attribute
+         WHERE attrelid = to_regclass($1) AND attname = $2 AND attnum > 0 AND NOT attisdropped",
+    )
+    .bind(table_name)
+    .bind(column)
+    .fetch_optional(pool)
+    .await?;
+    Ok(data_type.as_deref().and_then(CursorType::from_data_type))
+}
+
+// Highest cursor value in the table (among the rows matching `condition`), decoded according to the column type
+pub async fn max_value(
+    pool: &PgPool,
+    table_name: &str,
+    column: &str,
+    cursor_type: CursorType,
+    condition: Option<&str>,
+) -> Result<Option<Watermark>, Box<dyn Error>> {
+    // MAX over smallint/integer keeps the column type, integers are widened to bigint
+    let cast = if cursor_type == CursorType::Integer { "::bigint" } else { "" };
+    let filter = condition.map(|c| format!(" WHERE {}", c)).unwrap_or_default();
+    let query = format!("SELECT MAX({}){} FROM {}{}", quote_ident(column), cast, table_name, filter);
+    let row = sqlx::query(&query).fetch_one(pool).await?;
+    let watermark = match cursor_type {
+        CursorType::Timestamp => row.try_get::<Option<NaiveDateTime>, _>(0)?.map(Watermark::Timestamp),
+        CursorType::TimestampTz => row.try_get::<Option<DateTime<Utc>>, _>(0)?.map(Watermark::TimestampTz),
+        CursorType::Date => row.try_get::<Option<NaiveDate>, _>(0)?.map(Watermark::Date),
+        CursorType::Integer => row.try_get::<Option<i64>, _>(0)?.map(Watermark::Integer),
+    };
+    Ok(watermark)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookback_moves_temporal_bounds_back() {
+        let date = Watermark::Date(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap());
+        assert_eq!(date.lower_bound_sql(None).unwrap(), "'2024-03-01'::date");
+        assert_eq!(date.lower_bound_sql(Some("2 days")).unwrap(), "('2024-03-01'::date - '2 days'::interval)::date");
+        let timestamp = Watermark::Timestamp(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(12, 0, 0).unwrap());
+        assert_eq!(
+            timestamp.lower_bound_sql(Some("15 minutes")).unwrap(),
+            "('2024-03-01 12:00:00.000000'::timestamp - '15 minutes'::interval)"
+        );
+    }
+
+    #[test]
+    fn lookback_on_integer_cursor_is_an_error() {
+        assert_eq!(Watermark::Integer(42).lower_bound_sql(None).unwrap(), "42::bigint");
+        assert!(Watermark::Integer(42).lower_bound_sql(Some("15 minutes")).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// Per-table row filters (`"filter": "tenant_id = 42"`) combined with the incremental predicates.
+// A filter is an SQL boolean expression over the table's columns, checked against the source
+// before anything is copied.
+use sqlx::PgPool;
+use std::error::Error;
+
+// Rejects anything that could end the expression early and checks it against the table with EXPLAIN
+pub async fn validate(pool: &PgPool, table_name: &str, filter: &str) -> Result<(), Box<dyn Error>> {
+    if filter.trim().is_empty() {
+        return Err(format!("Filter of {} is empty", table_name).into());
+    }
+    if filter.contains(';') || filter.contains("--") || filter.contains("/*") {
+        return Err(format!("Filter of {} must be a single expression without ';' or comments: {}", table_name, filter).into());
+    }
+    // `a = 1) OR (b = 2` passes EXPLAIN inside the parentheses but escapes them in where_clause
+    if !balanced_parentheses(filter) {
+        return Err(format!("Filter of {} has unbalanced parentheses: {}", table_name, filter).into());
+    }
+    sqlx::query(&format!("EXPLAIN SELECT 1 FROM {} WHERE ({})", table_name, filter))
+        .execute(pool)
+        .await
+        .map_err(|e| format!("Invalid filter for {}: {}: {}", table_name, filter, e))?;
+    Ok(())
+}
+
+// Every `)` closes a `(` opened before it, outside of string literals (with E'' escapes),
+// dollar-quoted strings and quoted identifiers. False for unterminated quotes as well.
+fn balanced_parentheses(expression: &str) -> bool {
+    let chars: Vec<char> = expression.chars().collect();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '(' => depth += 1,
+            ')' => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            quote @ ('\'' | '"') => {
+                let escapes = quote == '\''
+                    && i > 0
+                    && matches!(chars[i - 1], 'E' | 'e')
+                    && (i < 2 || !is_word(chars[i - 2]));
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return false,
+                        Some('\\') if escapes => i += 1,
+                        // a doubled quote stands for itself
+                        Some(&c) if c == quote && chars.get(i + 1) == Some(&quote) => i += 1,
+                        Some(&c) if c == quote => break,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            // $tag$ ... $tag$, `$1` is a parameter and `a$b` part of an identifier
+            '$' if i == 0 || !is_word(chars[i - 1]) => {
+                let tag_end = (i + 1..chars.len()).find(|&j| !is_word(chars[j]));
+                if let Some(tag_end) = tag_end.filter(|&j| chars[j] == '$' && !chars.get(i + 1).is_some_and(char::is_ascii_digit)) {
+                    let tag: String = chars[i..=tag_end].iter().collect();
+                    let rest: String = chars[tag_end + 1..].iter().collect();
+                    let Some(close) = rest.find(&tag) else {
+                        return false;
+                    };
+                    i = tag_end + rest[..close].chars().count() + tag.chars().count();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    depth == 0
+}
+
+// `WHERE (a) AND (b)` or nothing if there are no conditions
+pub fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        return String::new();
+    }
+    let conditions: Vec<String> = conditions.iter().map(|c| format!("({})", c)).collect();
+    format!("WHERE {}", conditions.join(" AND "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_the_wrapping_parenthesis_is_rejected() {
+        assert!(!balanced_parentheses("a = 1) OR (b = 2"));
+        assert!(!balanced_parentheses("a = 1) OR true OR (b = 2"));
+        assert!(!balanced_parentheses("(a = 1"));
+    }
+
+    #[test]
+    fn parentheses_in_quotes_are_ignored() {
+        assert!(balanced_parentheses("tenant_id IN (1, 2) AND (status = 'open' OR status = 'new')"));
+        assert!(balanced_parentheses("name = ')' AND note = 'it''s ('"));
+        assert!(balanced_parentheses("\"odd)name\" = 1"));
+        assert!(balanced_parentheses("note = E'\\') OR (' AND x = 1"));
+        assert!(balanced_parentheses("body = $$ ) $$ AND tag = $t$ ( $t$"));
+        assert!(!balanced_parentheses("name = ')' ) OR ( x = 1"));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert!(!balanced_parentheses("name = 'abc"));
+        assert!(!balanced_parentheses("body = $$ abc"));
+    }
+
+    #[test]
+    fn where_clause_keeps_the_filter_inside_its_parentheses() {
+        let conditions = vec!["updated_at >= now()".to_string(), "tenant_id = 42 OR tenant_id = 7".to_string()];
+        assert_eq!(where_clause(&conditions), "WHERE (updated_at >= now()) AND (tenant_id = 42 OR tenant_id = 7)");
+        assert_eq!(where_clause(&[]), "");
+    }
+}
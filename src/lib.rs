@@ -0,0 +1,25 @@
+pub mod backfill;
+pub mod catalog;
+pub mod cdc;
+pub mod checksum;
+pub mod config;
+pub mod copy_format;
+pub mod cursor;
+pub mod filter;
+pub mod logging;
+pub mod masking;
+pub mod merge;
+pub mod pipe;
+pub mod retry;
+pub mod session;
+pub mod shard;
+pub mod slice;
+pub mod snapshot;
+pub mod spool;
+pub mod sql;
+pub mod state;
+pub mod throttle;
+pub mod transform;
+pub mod trigger_capture;
+pub mod verify;
+pub mod xmin;
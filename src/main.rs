@@ -1,267 +1,1132 @@
-use sqlx::{query, PgPool, Result, Row};
-use std::collections::HashMap;
+use sqlx::postgres::{PgCopyIn, PgPoolOptions};
+use sqlx::{query, Connection, PgConnection, PgPool, Postgres, Result, Row, Transaction};
+use std::cell::Cell;
 use std::env;
 use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
 use async_std::stream::StreamExt;
-use chrono::NaiveDateTime;
-
-async fn check_columns_exist(pool: &PgPool, table_name: &str) -> Result<(bool, bool, bool, Option<NaiveDateTime>)> {
-    // Check if the column `created_at`/`updated_at'/`id' exists in the table
-    let column_exists_query = format!(
-        "SELECT column_name FROM information_schema.columns WHERE table_name = '{}' AND column_name IN ('created_at', 'updated_at', 'id')",
-        table_name
-    );
-    println!("column_exists_query {:?}", &column_exists_query);
-
-    let rows = sqlx::query(&column_exists_query)
-        .fetch_all(pool)
-        .await?;
-
-    // Check if both columns are found
-    let mut created_at_exists = false;
-    let mut updated_at_exists = false;
+use bytes::Bytes;
+use futures::future::join_all;
+use tracing::{debug, error, info, info_span, Instrument};
+use postgres_data_sync::{backfill, catalog, cdc, checksum, copy_format, cursor, filter, logging, merge, pipe, retry, session, shard, slice, state, trigger_capture, verify, xmin};
+use postgres_data_sync::config::{BackfillConfig, CopyFormat, SessionSettings, SyncConfig, TableConfig};
+use postgres_data_sync::cursor::CursorType;
+use postgres_data_sync::snapshot::{self, ExportedSnapshot};
+use postgres_data_sync::masking::Masker;
+use postgres_data_sync::spool::Spool;
+use postgres_data_sync::throttle::Throttle;
+use postgres_data_sync::transform::TransformPipeline;
+use postgres_data_sync::xmin::SourceSnapshot;
+use postgres_data_sync::sql::{column_list, quote_ident};
+
+async fn check_columns_exist(pool: &PgPool, table: &TableConfig) -> Result<(Vec<(String, CursorType)>, bool), Box<dyn Error>> {
+    // Check if the column `created_at`/`updated_at'/`id' (or the configured cursor column) exists in the table
+    let cursor_candidates = match &table.cursor_column {
+        Some(column) => vec![column.clone()],
+        None => vec!["created_at".to_string(), "updated_at".to_string()],
+    };
+    let mut columns = cursor_candidates.clone();
+    columns.push("id".to_string());
+
+    let rows = sqlx::query(
+        "SELECT attname::text AS column_name, format_type(atttypid, NULL) AS data_type
+         FROM pg_attribute
+         WHERE attrelid = to_regclass($1) AND attname = ANY($2) AND attnum > 0 AND NOT attisdropped
+         ORDER BY array_position($2, attname::text)",
+    )
+    .bind(&table.name)
+    .bind(&columns)
+    .fetch_all(pool)
+    .await?;
+
+    // Cursor columns with their types, in the order of preference
+    let mut cursor_columns = Vec::new();
     let mut id_exists = false;
 
     for row in rows {
         let column_name: String = row.try_get("column_name")?;
-        if column_name == "created_at" {
-            created_at_exists = true;
-        } else if column_name == "updated_at" {
-            updated_at_exists = true;
-        } else if column_name == "id" {
+        let data_type: String = row.try_get("data_type")?;
+        if column_name == "id" {
             id_exists = true;
         }
+        if !cursor_candidates.contains(&column_name) {
+            continue;
+        }
+        match CursorType::from_data_type(&data_type) {
+            Some(cursor_type) => cursor_columns.push((column_name, cursor_type)),
+            None if table.cursor_column.is_some() => {
+                return Err(format!("Cursor column {}.{} has unsupported type {}", table.name, column_name, data_type).into());
+            }
+            None => info!(table = %table.name, column = %column_name, data_type = %data_type, "skipping as cursor, unsupported type"),
+        }
     }
-    let last_created_at: Option<NaiveDateTime> =  if created_at_exists {
-        let query = format!("SELECT MAX(created_at) FROM {} WHERE created_at IS NOT NULL", table_name);
-        let row = sqlx::query(&query)
-            .fetch_one(pool)
-            .await?;
-        row.try_get::<Option<NaiveDateTime>, _>(0).ok().flatten()
-    } else {
-        None
+    if let Some(column) = &table.cursor_column {
+        if cursor_columns.is_empty() {
+            return Err(format!("Cursor column {}.{} does not exist", table.name, column).into());
+        }
+    }
+    // Checked before there is a watermark to take the lookback off
+    if let (Some(lookback), Some((column, _))) = (&table.lookback, cursor_columns.iter().find(|(_, t)| *t == CursorType::Integer)) {
+        return Err(format!("Table {} has lookback {} but its cursor column {} is an integer", table.name, lookback, column).into());
+    }
+
+    Ok((cursor_columns, id_exists))
+}
+
+// `*` or the columns kept by the table's include/exclude lists
+async fn select_list(source_pool: &PgPool, table: &TableConfig) -> Result<String, Box<dyn Error>> {
+    Ok(match catalog::selected_columns(source_pool, table).await? {
+        Some(columns) => column_list(&columns),
+        None => "*".to_string(),
+    })
+}
+
+// What query_update selects, rendered with an extra predicate per slice when the table is copied in parallel
+struct Extract {
+    table_name: String,
+    select_list: String,
+    predicates: Vec<String>,
+    order_by: Option<String>,
+    // cursor tables: the cursor predicate the extract covers, `TRUE` for a full copy
+    cursor_condition: Option<String>,
+}
+
+impl Extract {
+    fn query(&self, slice: Option<&str>) -> String {
+        let mut predicates = self.predicates.clone();
+        predicates.extend(slice.map(str::to_string));
+        let order_by = self.order_by.as_ref().map(|column| format!(" ORDER BY {} ASC", column)).unwrap_or_default();
+        format!("SELECT {} FROM {} {}{}", self.select_list, self.table_name, filter::where_clause(&predicates), order_by)
+    }
+}
+
+// Rows at or above the highest cursor values in `pool`'s table (among the rows matching
+// `condition`), None when it has none. The lookback window before the watermark is re-scanned,
+// the upsert in transfer_table makes the overlap harmless.
+async fn cursor_condition(
+    pool: &PgPool,
+    table: &TableConfig,
+    cursor_columns: &[(String, CursorType)],
+    condition: Option<&str>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut conditions = Vec::new();
+    for (column, cursor_type) in cursor_columns {
+        if let Some(watermark) = cursor::max_value(pool, &table.name, column, *cursor_type, condition).await? {
+            let lower_bound = watermark
+                .lower_bound_sql(table.lookback.as_deref())
+                .map_err(|err| format!("Table {} cursor column {}: {}", table.name, column, err))?;
+            conditions.push(format!("{} >= {}", quote_ident(column), lower_bound));
+        }
+    }
+    Ok((!conditions.is_empty()).then(|| conditions.join(" OR ")))
+}
+
+// Returns the extraction query and, for xmin based tables, the watermark to store once the copy succeeded.
+// With several targets the query covers the one furthest behind, the others upsert rows they already have
+// (see extract_groups).
+async fn query_update(
+    source_pool: &PgPool,
+    targets: &[&Target],
+    table: &TableConfig,
+    source_id: Option<&str>,
+    run_snapshot: Option<SourceSnapshot>,
+) -> Result<(Extract, Option<u64>), Box<dyn Error>> {
+    let table_name = table.name.as_str();
+    let (cursor_columns, id_exists) = check_columns_exist(source_pool, table).await?;
+    let select_list = select_list(source_pool, table).await?;
+
+    if let Some((order_column, _)) = cursor_columns.first() {
+        // Watermarks are the highest values the target already has (from this source when consolidating
+        // several), without any the whole table is copied
+        // A condition pinned by an incomplete sliced copy takes precedence over the target rows
+        let source_condition = source_id.map(shard::source_condition);
+        let state_key = shard::state_key(table_name, source_id);
+        let mut target_conditions: Vec<String> = Vec::new();
+        let mut full_copy = false;
+        for target in targets {
+            let condition = match state::load_cursor_condition(&target.pool, &state_key).await? {
+                Some(pinned) => Some(pinned),
+                None => cursor_condition(&target.pool, table, &cursor_columns, source_condition.as_deref()).await?,
+            };
+            match condition {
+                None => full_copy = true,
+                Some(condition) if !target_conditions.contains(&condition) => target_conditions.push(condition),
+                Some(_) => {}
+            }
+        }
+        let cursor_condition = (!full_copy && !target_conditions.is_empty()).then(|| target_conditions.join(" OR "));
+        let mut predicates: Vec<String> = cursor_condition.iter().cloned().collect();
+        predicates.extend(table.filter.clone());
+        let extract = Extract {
+            table_name: table_name.to_string(),
+            select_list,
+            predicates,
+            order_by: Some(quote_ident(order_column)),
+            cursor_condition: Some(cursor_condition.unwrap_or_else(|| "TRUE".to_string())),
+        };
+        debug!(table = %table_name, sql = %logging::loggable_sql(table, &extract.query(None)), "cursor query");
+        return Ok((extract, None));
+    }
+
+    // No timestamps to go by: pick up rows written by transactions since the previous run's snapshot.
+    // When the run reads from an exported snapshot the watermark has to be that snapshot's.
+    let snapshot = match run_snapshot {
+        Some(snapshot) => snapshot,
+        None => xmin::current_snapshot(source_pool).await?,
     };
+    let mut watermarks = Vec::new();
+    for target in targets {
+        watermarks.push(state::load_xmin_watermark(&target.pool, &shard::state_key(table_name, source_id)).await?);
+    }
+    // A target without a watermark needs the whole table
+    let watermark = watermarks.into_iter().collect::<Option<Vec<u64>>>().and_then(|w| w.into_iter().min());
+    let mut predicates: Vec<String> = xmin::changed_since(watermark, &snapshot).into_iter().collect();
+    predicates.extend(table.filter.clone());
+    let extract = Extract {
+        table_name: table_name.to_string(),
+        select_list,
+        predicates,
+        order_by: id_exists.then(|| "id".to_string()),
+        cursor_condition: None,
+    };
+    debug!(table = %table_name, sql = %logging::loggable_sql(table, &extract.query(None)), "xmin query");
+
+    Ok((extract, Some(snapshot.xmin)))
+}
+
+// sqlx's default of 10 connections per pool, more when tables are copied in many slices
+fn pool_options(config: &SyncConfig) -> PgPoolOptions {
+    PgPoolOptions::new().max_connections((config.max_slices() as u32 + 2).max(10))
+}
+
+// A target database of the run, `"targets"` in the config or POSTGRES_URL_TARGET
+struct Target {
+    name: String,
+    pool: PgPool,
+}
+
+async fn connect_targets(config: &SyncConfig) -> Result<Vec<Target>, Box<dyn Error>> {
+    let settings = &config.session.target;
+    if config.targets.is_empty() {
+        let postgres_url_target = env::var("POSTGRES_URL_TARGET").unwrap_or_else(|_| "nothing here".to_string());
+        let name = "target".to_string();
+        info!(target = %name, url = %logging::redact_url(&postgres_url_target), "connecting to the target");
+        let target_ssl = postgres_url_target + "?sslmode=require";
+        let pool = pool_options(config).connect_with(session::connect_options(&target_ssl, settings)?).await?;
+        return Ok(vec![Target { name, pool }]);
+    }
+    let mut targets = Vec::with_capacity(config.targets.len());
+    for target in &config.targets {
+        let url = env::var(&target.url_env).map_err(|_| format!("Target {}: {} is not set", target.name, target.url_env))?;
+        info!(target = %target.name, url = %logging::redact_url(&url), "connecting to the target");
+        let pool = pool_options(config).connect_with(session::connect_options(&(url + "?sslmode=require"), settings)?).await?;
+        targets.push(Target { name: target.name.clone(), pool });
+    }
+    Ok(targets)
+}
+
+// What every table of a run shares
+struct Run<'a> {
+    source_pool: &'a PgPool,
+    // set when consolidating several sources, the `_source_id` of the rows read by this run
+    source_id: Option<&'a str>,
+    config: &'a SyncConfig,
+    // exported snapshot all tables are read from with consistent_snapshot (or a parallel table's slices)
+    snapshot: Option<&'a ExportedSnapshot>,
+    // shared by all sources of the run, file names include the source id
+    spool: Option<&'a Spool>,
+}
+
+impl Run<'_> {
+    // Key of the table's watermarks and backfill plan on the targets
+    fn state_key(&self, table_name: &str) -> String {
+        shard::state_key(table_name, self.source_id)
+    }
+}
+
+// Read in a transaction on the source, importing the run's snapshot if there is one
+async fn begin_source<'c>(run: &Run<'_>, conn: &'c mut PgConnection) -> Result<Transaction<'c, Postgres>, Box<dyn Error>> {
+    let mut source_tx = conn.begin().await?;
+    if let Some(snapshot) = run.snapshot {
+        snapshot::import(&mut source_tx, &snapshot.id).await?;
+    }
+    Ok(source_tx)
+}
+
+// State recorded on the target in the same transaction as the rows it covers
+#[derive(Clone, Copy)]
+enum Checkpoint<'a> {
+    // a slice of a copy, its state is recorded once all slices are in
+    None,
+    // cursor tables derive their watermark from the target rows themselves, a complete copy drops
+    // the condition an incomplete sliced copy pinned
+    Cursor,
+    XminWatermark(u64),
+    BackfillRange(i32),
+    // the copied rows are all the source has in this key range (a target condition), the
+    // target rows in it that weren't copied are deleted
+    RepairRange(&'a str),
+}
+
+// Target side of a load: one transaction for the staging COPY, the upsert and the checkpoint, so
+// a failure anywhere leaves the target as it was (the transaction rolls back when dropped)
+struct Load {
+    tx: Transaction<'static, Postgres>,
+    load_table: String,
+    primary_key: Vec<String>,
+    selected_columns: Option<Vec<String>>,
+}
+
+// Outcome of a copy per target, in the order the targets were passed in
+type TargetResults = Vec<Result<(), Box<dyn Error>>>;
 
-    Ok((created_at_exists, updated_at_exists, id_exists,last_created_at ))
+// Columns named in the COPY statements, None when whole rows are copied. Consolidated targets have
+// an extra `_source_id` column, so the copied columns are always named there.
+async fn copied_columns(source_pool: &PgPool, table: &TableConfig, source_id: Option<&str>) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let selected_columns = catalog::selected_columns(source_pool, table).await?;
+    if source_id.is_some() && selected_columns.is_none() {
+        return Ok(Some(catalog::column_names(source_pool, &table.name).await?));
+    }
+    Ok(selected_columns)
 }
 
-async fn query_update(source_pool: &PgPool, target_pool: &PgPool, table_name: &str)  -> Result<String> {
-    let (created_at_exists, updated_at_exists, id_exists, last_created_at) = check_columns_exist(source_pool, table_name).await?;
+// The copied columns and, on consolidated targets, `_source_id`
+fn merged_columns(run: &Run<'_>, mut columns: Vec<String>) -> Vec<String> {
+    if run.source_id.is_some() {
+        columns.push(shard::SOURCE_ID_COLUMN.to_string());
+    }
+    columns
+}
+
+async fn prepare_load(run: &Run<'_>, target: &Target, table: &TableConfig) -> Result<Load, Box<dyn Error>> {
+    let table_name = table.name.as_str();
+    let mut tx = target.pool.begin().await?;
 
-    let mut query = if created_at_exists && updated_at_exists {
-        format!("SELECT * FROM {} WHERE created_at >= '{:?}' or updated_at >= '{:?}' ORDER BY created_at ASC", table_name, last_created_at.unwrap(), last_created_at.unwrap())
-    } else if created_at_exists && !updated_at_exists {
-        format!("SELECT * FROM {} WHERE created_at >= '{:?}' ORDER BY created_at ASC", table_name, last_created_at.unwrap())
-    } else if !created_at_exists && !updated_at_exists && id_exists {
-        format!("SELECT * FROM {} ORDER BY id ASC", table_name)
+    // Load through the temp table and upsert by primary key, without a key COPY straight into the table
+    let primary_key = catalog::primary_key_columns(&target.pool, table_name).await?;
+    let selected_columns = copied_columns(run.source_pool, table, run.source_id).await?;
+    if let Some(columns) = &selected_columns {
+        if let Some(key) = primary_key.iter().find(|k| !columns.contains(k) && *k != shard::SOURCE_ID_COLUMN) {
+            return Err(format!("Primary key column {}.{} is excluded but needed for the upsert", table_name, key).into());
+        }
+    }
+    let load_table = if primary_key.is_empty() {
+        info!(table = %table_name, target = %target.name, "no primary key, appending rows");
+        table_name.to_string()
     } else {
-        format!("SELECT * FROM {} ", table_name)
+        // Columns that aren't copied stay out of the staging table, `_source_id` gets a default there
+        let staging_columns = selected_columns.clone().map(|columns| merged_columns(run, columns));
+        let staging = merge::create_staging_table(&mut tx, table_name, staging_columns.as_deref()).await?;
+        if let Some(source_id) = run.source_id {
+            shard::set_staging_source_id(&mut tx, &staging, source_id).await?;
+        }
+        staging
     };
+    Ok(Load { tx, load_table, primary_key, selected_columns })
+}
 
-    Ok(query)
-}
-
-async fn list_tables_create(source_pool: &PgPool, target_pool: &PgPool) -> Result<Vec<String>> {
-    // Don't use it for now as trying on the single tables
-    // Find tables which are not postgres own tables
-    // let tables: Vec<String> = sqlx::query_scalar(
-    //     "SELECT tablename
-    //      FROM pg_catalog.pg_tables
-    //      WHERE schemaname NOT IN ('pg_catalog', 'information_schema');"
-    // )
-    //     .fetch_all(&*source_pool)
-    //     .await?;
-    //
-    // println!("Tables to transfer: {:?}", tables);
-
-    // Store schema for each table from above
-    let mut table_schemas: HashMap<String, String> = HashMap::new();
-    let tables = vec!["table1".to_string(), "table2".to_string()];
-    for table in &tables {
-        // Query to get the schema definition from the source database
-        // Need to create a temp table, then insert from there, as COPY doesn't support ON CONFLICT
-        let temp_name = format!("TEMPORARY TABLE transform.{}_sqlx", &table);  //'CREATE TEMPORARY TABLE transform.{}_sqlx (' ||
-        let physical_name = format!("TABLE IF NOT EXISTS public.{}", &table);  //'CREATE TABLE IF NOT EXISTS public.{} (' ||
-        let schema_query_temp = format!(
-            "SELECT
-            'CREATE {} (' ||
-            string_agg(
-                column_name || ' ' ||
-                data_type ||
-                CASE
-                    WHEN character_maximum_length IS NOT NULL THEN '(' || character_maximum_length || ')'
-                    ELSE ''
-                END ||
-                CASE
-                    WHEN is_nullable = 'NO' THEN ' NOT NULL'
-                    ELSE ''
-                END,
-                ', '
-            ) ||
-            ');' AS create_table_statement
-        FROM information_schema.columns
-        WHERE table_name = '{}';" , temp_name, table
-        );
-        let schema_query_physical = format!(
-            "SELECT
-            'CREATE {} (' ||
-            string_agg(
-                column_name || ' ' ||
-                data_type ||
-                CASE
-                    WHEN character_maximum_length IS NOT NULL THEN '(' || character_maximum_length || ')'
-                    ELSE ''
-                END ||
-                CASE
-                    WHEN is_nullable = 'NO' THEN ' NOT NULL'
-                    ELSE ''
-                END,
-                ', '
-            ) ||
-            ');' AS create_table_statement
-        FROM information_schema.columns
-        WHERE table_name = '{}';" , physical_name, table
-        );
-        println!("{:?} schema_query" ,&schema_query_temp);
-
-        match sqlx::query_as::<_, (String,)>(&schema_query_temp)
-            .fetch_one(&*source_pool)
-            .await {
-            Ok((schema,)) => {
-                table_schemas.insert(table.clone(), schema);
+// COPY IN to the target database (streaming data), naming the columns when only some are copied
+async fn start_copy_in(load: &mut Load, copy_format: CopyFormat) -> Result<PgCopyIn<&mut PgConnection>, Box<dyn Error>> {
+    let target_columns = match &load.selected_columns {
+        Some(columns) => format!(" ({})", column_list(columns)),
+        None => String::new(),
+    };
+    let statement = format!("COPY {}{} FROM STDIN {}", load.load_table, target_columns, copy_format.copy_options());
+    Ok(load.tx.copy_in_raw(&statement).await?)
+}
+
+// Rows of a chunk after the table's transforms and masking, the chunk itself when there are none
+fn transformed(pipeline: &mut TransformPipeline, data: Bytes) -> Result<Bytes, Box<dyn Error>> {
+    if pipeline.is_empty() {
+        Ok(data)
+    } else {
+        Ok(Bytes::from(pipeline.process(&data)?))
+    }
+}
+
+// Sends a chunk to every target still loading, concurrently. A target whose COPY fails drops out
+// of the rest of the stream with its error recorded; false once no target is left.
+async fn send_to_targets(
+    sinks: &mut [Option<PgCopyIn<&mut PgConnection>>],
+    errors: &mut [Option<Box<dyn Error>>],
+    data: &[u8],
+) -> bool {
+    if !data.is_empty() {
+        let results = join_all(sinks.iter_mut().map(|sink| async move {
+            match sink {
+                Some(copy_in) => copy_in.send(data).await.map(|_| ()),
+                None => Ok(()),
             }
-            Err(e) => {
-                eprintln!("Error fetching schema for table {}: {}", table, e);
+        }))
+        .await;
+        for ((sink, error), result) in sinks.iter_mut().zip(errors.iter_mut()).zip(results) {
+            if let Err(err) = result {
+                *sink = None;
+                *error = Some(Box::new(err));
             }
         }
     }
+    sinks.iter().any(Option::is_some)
+}
 
-    // Recreate each table in target database (if not exists ) for both: physical table and update temp table
-    for (table, schema) in &table_schemas {
-        let table_load_physical = format!("public.{}", table); // will add later
-        let table_load_sqlx = format!("transform.{}_sqlx", table);
-        println!("Creating update table {} in target database ", table_load_sqlx);
-        // let schema_with_check = schema.replace("CREATE TABLE", "CREATE TABLE IF NOT EXISTS"); // for the cases when the table exists
-
-        let check_table_query = format!("SELECT to_regclass('{}');", table_load_sqlx);
-        let table_exists: Option<String> = sqlx::query_scalar(&check_table_query)
-            .fetch_one(&*target_pool)
-            .await
-            .ok();
+// Upserts the staged rows, records the checkpoint and commits it all at once
+async fn finish_load(
+    run: &Run<'_>,
+    target: &Target,
+    table: &TableConfig,
+    mut load: Load,
+    copied: u64,
+    checkpoint: Checkpoint<'_>,
+) -> Result<(), Box<dyn Error>> {
+    let table_name = table.name.as_str();
+    if let Checkpoint::RepairRange(condition) = checkpoint {
+        if load.primary_key.is_empty() {
+            return Err(format!("Table {} has no primary key on {}, it can't be repaired", table_name, target.name).into());
+        }
+        let deleted = merge::delete_missing(&mut load.tx, table_name, &load.load_table, &load.primary_key, condition).await?;
+        info!(table = %run.state_key(table_name), target = %target.name, rows = deleted, "deleted rows missing on the source");
+    }
+    if !load.primary_key.is_empty() {
+        // Columns that aren't copied keep their target values
+        let whole_rows = load.selected_columns.is_none();
+        let columns = match load.selected_columns {
+            Some(columns) => merged_columns(run, columns),
+            None => catalog::column_names(&target.pool, table_name).await?,
+        };
+        let merged = merge::merge_staging_table(&mut load.tx, table_name, &load.load_table, &columns, &load.primary_key, whole_rows).await?;
+        info!(table = %run.state_key(table_name), target = %target.name, rows = copied, upserted = merged, "copied rows");
+    }
+    match checkpoint {
+        Checkpoint::None | Checkpoint::RepairRange(_) => {}
+        Checkpoint::Cursor => state::clear_cursor_condition(&mut *load.tx, &run.state_key(table_name)).await?,
+        Checkpoint::XminWatermark(watermark) => state::save_xmin_watermark(&mut *load.tx, &run.state_key(table_name), watermark).await?,
+        Checkpoint::BackfillRange(range_no) => backfill::complete_range(&mut *load.tx, &run.state_key(table_name), range_no).await?,
+    }
+    load.tx.commit().await?;
+    Ok(())
+}
 
-        if table_exists.is_none() {
-            if let Err(e) = sqlx::query(schema).execute(&*target_pool).await {
-                println!("Failed to create table {}: {}", table, e);
-            } else {
-                println!("Successfully created table {}.", table);
-            }
+// Copies the query's rows to the targets. An error is returned for failures on the source side,
+// failures of single targets are returned per target.
+async fn transfer_table(
+    run: &Run<'_>,
+    table: &TableConfig,
+    custom_query: &str,
+    copy_format: CopyFormat,
+    targets: &[&Target],
+    checkpoint: Checkpoint<'_>,
+) -> Result<TargetResults, Box<dyn Error>> {
+    // With a spool the source is read into a local file and released before the targets are touched
+    if let Some(spool) = run.spool {
+        let path = spool.path_for(&run.state_key(&table.name), custom_query, copy_format);
+        if spool.exists(&path).await? {
+            info!(table = %table.name, path = %path.display(), "loading existing spool file");
         } else {
-            println!("Table {} already exists. Skipping creation.", table);
-        }
-    }
-    // won't do it for now as checking the load
-    // for table in &tables {
-    //     let index_query = format!(
-    //         "SELECT indexdef FROM pg_indexes WHERE schemaname = 'public' AND tablename = '{}';",
-    //         table
-    //     );
-    //
-    //     if let Ok(rows) = sqlx::query(&index_query).fetch_all(&*source_pool).await {
-    //         for row in rows {
-    //             let indexdef: String = row.get("indexdef");
-    //             println!("Recreating index for table {}: {}", table, indexdef);
-    //             if let Err(e) = sqlx::query(&indexdef).execute(&*target_pool).await {
-    //                 println!("Failed to create index for table {}: {}", table, e);
-    //             }
-    //         }
-    //     }
-    // }
-    //
-    // // Fetch constraints for each table
-    // for table in &tables {
-    //     let constraint_query = format!(
-    //         "SELECT conname, pg_catalog.pg_get_constraintdef(c.oid)
-    //          FROM pg_catalog.pg_constraint c
-    //          JOIN pg_catalog.pg_class t ON t.oid = c.conrelid
-    //          JOIN pg_catalog.pg_namespace n ON n.oid = t.relnamespace
-    //          WHERE n.nspname = 'public' AND t.relname = '{}';",
-    //         table
-    //     );
-    //
-    //     if let Ok(rows) = sqlx::query(&constraint_query).fetch_all(&*source_pool).await {
-    //         for row in rows {
-    //             let conname: String = row.get("conname");
-    //             let condef: String = row.get("pg_catalog.pg_get_constraintdef");
-    //             println!("Recreating constraint {} for table {}: {}", conname, table, condef);
-    //             let alter_query = format!("ALTER TABLE {} ADD CONSTRAINT {} {}", table, conname, condef);
-    //             if let Err(e) = sqlx::query(&alter_query).execute(&*target_pool).await {
-    //                 println!("Failed to create constraint {} for table {}: {}", conname, table, e);
-    //             }
-    //         }
-    //     }
-    // }
-
-    println!("All tables processed/recreated.");
-    Ok((tables))
-    // Ok(())
+            spool_table(run, table, custom_query, copy_format, spool, &path).await?;
+        }
+        let loads = targets.iter().map(|target| load_from_spool(run, target, table, copy_format, spool, &path, checkpoint));
+        let results: TargetResults = join_all(loads).await;
+        // Kept for the targets to retry otherwise
+        if results.iter().all(Result::is_ok) {
+            spool.remove(&path).await?;
+        }
+        return Ok(results);
+    }
+    stream_table(run, table, custom_query, copy_format, targets, checkpoint).await
 }
 
-async fn transfer_table(
-    source_pool: &PgPool,
-    target_pool: &PgPool,
-    table_name: &str,
+// Source reads get a connection of their own: a COPY OUT abandoned halfway leaves unread data on
+// it, so unless every target got the rows the connection is closed instead of going back to the pool
+async fn stream_table(
+    run: &Run<'_>,
+    table: &TableConfig,
     custom_query: &str,
-) -> Result<(), Box<dyn Error>> {
-    // Acquire a connection from sqlx pool (for non-COPY queries)
-    let mut source_conn = source_pool.acquire().await?;
-    let mut target_conn = target_pool.acquire().await?;
-    // COPY OUT from the source database (streaming data) - passing custom query with conditions from query_update
-    let mut copy_out = source_conn.copy_out_raw(&format!("COPY ({}) TO STDOUT WITH CSV HEADER", custom_query)).await?;
+    copy_format: CopyFormat,
+    targets: &[&Target],
+    checkpoint: Checkpoint<'_>,
+) -> Result<TargetResults, Box<dyn Error>> {
+    let mut conn = run.source_pool.acquire().await?;
+    let results = copy_to_targets(run, &mut conn, table, custom_query, copy_format, targets, checkpoint).await;
+    if !matches!(&results, Ok(results) if results.iter().all(Result::is_ok)) {
+        conn.close_on_drop();
+    }
+    results
+}
 
-    // COPY IN to the target database (streaming data)
-    let mut copy_in = target_conn.copy_in_raw(&format!("COPY {} FROM STDIN WITH CSV HEADER", table_name)).await?;
+// COPY OUT teed into a COPY IN per target, the source transaction stays open until the targets have the rows
+async fn copy_to_targets(
+    run: &Run<'_>,
+    conn: &mut PgConnection,
+    table: &TableConfig,
+    custom_query: &str,
+    copy_format: CopyFormat,
+    targets: &[&Target],
+    checkpoint: Checkpoint<'_>,
+) -> Result<TargetResults, Box<dyn Error>> {
+    let table_name = table.name.as_str();
+    let started = Instant::now();
+    // Rows are parsed and re-encoded only when the table has column transforms or masking rules
+    let mut pipeline = TransformPipeline::for_table(table)?;
+    // Wait for the source to be within its load thresholds before opening the transaction
+    let mut throttle = Throttle::new(run.config.throttle_for(table));
+    throttle.wait_for_source(run.source_pool, table_name).await?;
+    let mut source_tx = begin_source(run, conn).await?;
+    // A target that fails keeps its error here and drops out, the others carry on
+    let mut loads = Vec::with_capacity(targets.len());
+    let mut errors: Vec<Option<Box<dyn Error>>> = Vec::with_capacity(targets.len());
+    for load in join_all(targets.iter().map(|target| prepare_load(run, target, table))).await {
+        match load {
+            Ok(load) => {
+                loads.push(Some(load));
+                errors.push(None);
+            }
+            Err(err) => {
+                loads.push(None);
+                errors.push(Some(err));
+            }
+        }
+    }
+
+    // COPY OUT from the source database (streaming data) - passing custom query with conditions from query_update
+    let mut copy_out = source_tx.copy_out_raw(&format!("COPY ({}) TO STDOUT {}", custom_query, copy_format.copy_options())).await?;
+    let mut sinks = Vec::with_capacity(loads.len());
+    for (load, error) in loads.iter_mut().zip(errors.iter_mut()) {
+        sinks.push(match load {
+            Some(load) => start_copy_in(load, copy_format).await.map_err(|err| *error = Some(err)).ok(),
+            None => None,
+        });
+    }
 
     // let mut buffer = vec![0; 8192]; // A buffer for chunking data
     // https://github.com/launchbadge/sqlx/issues/36
     // https://github.com/launchbadge/sqlx/blob/82d332f4b487440b4c2bd5d54a5f17dcc1abc92c/sqlx-postgres/src/copy.rs#L58
+    // The reader (COPY OUT, throttle, transforms) and the writer (COPY IN to the targets) run
+    // concurrently with up to copy_buffer_bytes between them. A read error cancels the writer,
+    // the writer stopping because every target failed makes the reader's next send fail.
+    let (sender, receiver) = pipe::channel(run.config.copy_buffer_bytes);
+    let mut bytes = 0u64;
+    let read = async {
+        let sender = sender;
+        while let Some(chunk) = copy_out.next().await {
+            let data = chunk.inspect_err(|err| error!(table = %table_name, error = %err, "error during streaming"))?;
+            // println!("data {:?}", &data);
+            bytes += data.len() as u64;
+            throttle.wait_for_source(run.source_pool, table_name).await?;
+            throttle.consume(&data).await;
+            if sender.send(transformed(&mut pipeline, data)?).await.is_err() {
+                return Ok(false);
+            }
+        }
+        if !pipeline.is_empty() && sender.send(Bytes::from(pipeline.finish()?)).await.is_err() {
+            return Ok(false);
+        }
+        Ok::<_, Box<dyn Error>>(true)
+    };
+    let write = async {
+        let mut receiver = receiver;
+        let mut targets_left = sinks.iter().any(Option::is_some);
+        while targets_left {
+            let Some(chunk) = receiver.recv().await else { break };
+            targets_left = send_to_targets(&mut sinks, &mut errors, &chunk).await;
+        }
+        Ok::<_, Box<dyn Error>>(targets_left)
+    };
+    let (read_all, targets_left) = tokio::try_join!(read, write)?;
+    // Every target failed, the source transaction is rolled back on drop
+    if !read_all || !targets_left {
+        return Ok(errors.into_iter().map(|error| error.map_or(Ok(()), Err)).collect());
+    }
+    // Finish the COPY operations on the target databases
+    let finished = join_all(sinks.into_iter().map(|sink| async move {
+        match sink {
+            Some(copy_in) => Some(copy_in.finish().await),
+            None => None,
+        }
+    }))
+    .await;
+    let mut copied = Vec::with_capacity(finished.len());
+    for (result, error) in finished.into_iter().zip(errors.iter_mut()) {
+        copied.push(match result {
+            Some(Ok(rows)) => Some(rows),
+            Some(Err(err)) => {
+                *error = Some(Box::new(err));
+                None
+            }
+            None => None,
+        });
+    }
+    drop(copy_out);
+    source_tx.commit().await?;
+    info!(table = %run.state_key(table_name), bytes, duration_ms = started.elapsed().as_millis() as u64, "read from the source");
+
+    let loads = loads.into_iter().zip(copied).zip(targets).map(|((load, copied), target)| async move {
+        match (load, copied) {
+            (Some(load), Some(copied)) => Some(finish_load(run, target, table, load, copied, checkpoint).await),
+            _ => None,
+        }
+    });
+    for (result, error) in join_all(loads).await.into_iter().zip(errors.iter_mut()) {
+        if let Some(Err(err)) = result {
+            *error = Some(err);
+        }
+    }
+    Ok(errors.into_iter().map(|error| error.map_or(Ok(()), Err)).collect())
+}
+
+// Like stream_table, the source connection is closed if the read didn't complete
+async fn spool_table(
+    run: &Run<'_>,
+    table: &TableConfig,
+    custom_query: &str,
+    copy_format: CopyFormat,
+    spool: &Spool,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = run.source_pool.acquire().await?;
+    let result = copy_to_spool(run, &mut conn, table, custom_query, copy_format, spool, path).await;
+    if result.is_err() {
+        conn.close_on_drop();
+        spool.discard(path).await?;
+    }
+    result
+}
+
+// COPY OUT into a spool file, the source transaction ends when the file is complete
+async fn copy_to_spool(
+    run: &Run<'_>,
+    conn: &mut PgConnection,
+    table: &TableConfig,
+    custom_query: &str,
+    copy_format: CopyFormat,
+    spool: &Spool,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let table_name = table.name.as_str();
+    let started = Instant::now();
+    // Transforms and masking are applied before writing, so masked values never reach the disk
+    let mut pipeline = TransformPipeline::for_table(table)?;
+    let mut throttle = Throttle::new(run.config.throttle_for(table));
+    throttle.wait_for_source(run.source_pool, table_name).await?;
+    let mut source_tx = begin_source(run, conn).await?;
+    let mut writer = spool.writer(path);
+    let mut copy_out = source_tx.copy_out_raw(&format!("COPY ({}) TO STDOUT {}", custom_query, copy_format.copy_options())).await?;
     while let Some(chunk) = copy_out.next().await {
-        match chunk {
-            Ok(data) => {
-                // println!("data {:?}", &data);
-                copy_in.send(data).await?; }
+        let data = chunk?;
+        throttle.wait_for_source(run.source_pool, table_name).await?;
+        throttle.consume(&data).await;
+        writer.write(transformed(&mut pipeline, data)?).await?;
+    }
+    if !pipeline.is_empty() {
+        writer.write(Bytes::from(pipeline.finish()?)).await?;
+    }
+    drop(copy_out);
+    source_tx.commit().await?;
+    let bytes = writer.finish().await?;
+    info!(table = %table_name, bytes, path = %path.display(), duration_ms = started.elapsed().as_millis() as u64, "spooled");
+    Ok(())
+}
+
+// The spool file already has the transformed rows, every target reads it on its own
+async fn load_from_spool(
+    run: &Run<'_>,
+    target: &Target,
+    table: &TableConfig,
+    copy_format: CopyFormat,
+    spool: &Spool,
+    path: &Path,
+    checkpoint: Checkpoint<'_>,
+) -> Result<(), Box<dyn Error>> {
+    let mut load = prepare_load(run, target, table).await?;
+    let mut copy_in = start_copy_in(&mut load, copy_format).await?;
+    let mut reader = spool.reader(path);
+    while let Some(chunk) = reader.read().await? {
+        copy_in.send(chunk).await?;
+    }
+    let copied = copy_in.finish().await?;
+    finish_load(run, target, table, load, copied, checkpoint).await
+}
+
+// Copies a table or backfill range to the targets, retrying the targets that failed with transient
+// errors (and all of them after transient source errors). Returns the targets that failed for good.
+async fn sync_table<'t>(
+    run: &Run<'_>,
+    table: &TableConfig,
+    custom_query: &str,
+    copy_format: CopyFormat,
+    targets: &[&'t Target],
+    checkpoint: Checkpoint<'_>,
+    what: &str,
+) -> Result<Vec<(&'t Target, Box<dyn Error>)>, Box<dyn Error>> {
+    let mut backoff = retry::Backoff::new(&run.config.retry);
+    let mut pending = targets.to_vec();
+    let mut failed = Vec::new();
+    loop {
+        let results = match transfer_table(run, table, custom_query, copy_format, &pending, checkpoint).await {
+            Ok(results) => results,
+            Err(err) if backoff.retry(what, err.as_ref()).await => continue,
+            Err(err) => return Err(err),
+        };
+        let mut transient = Vec::new();
+        for (target, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(()) => {}
+                Err(err) if retry::is_transient(err.as_ref()) => transient.push((target, err)),
+                Err(err) => failed.push((target, err)),
+            }
+        }
+        let Some((_, err)) = transient.first() else {
+            return Ok(failed);
+        };
+        let names: Vec<&str> = transient.iter().map(|(target, _)| target.name.as_str()).collect();
+        if !backoff.retry(&format!("{} on {}", what, names.join(", ")), err.as_ref()).await {
+            failed.extend(transient);
+            return Ok(failed);
+        }
+        pending = transient.into_iter().map(|(target, _)| target).collect();
+    }
+}
+
+// Initial load of one target in ranges, each copied and checkpointed on its own so an interrupted
+// backfill resumes. Returns the error if the target failed, later ranges are left for the next run.
+async fn backfill_table(
+    run: &Run<'_>,
+    table: &TableConfig,
+    config: &BackfillConfig,
+    copy_format: CopyFormat,
+    target: &Target,
+) -> Result<Option<Box<dyn Error>>, Box<dyn Error>> {
+    let (source_pool, target_pool) = (run.source_pool, &target.pool);
+    let state_key = run.state_key(&table.name);
+    if !backfill::has_plan(target_pool, &state_key).await? {
+        // Changes made while the backfill runs are picked up by the incremental sync afterwards,
+        // from the xmin or the cursor values as of planning. The ranges are read at different times,
+        // so the target rows' highest cursor values can be past a row changed in an earlier range.
+        let (cursor_columns, _) = check_columns_exist(source_pool, table).await?;
+        let cursor_condition = cursor_condition(source_pool, table, &cursor_columns, None).await?;
+        let snapshot = match run.snapshot {
+            Some(snapshot) => snapshot.xids,
+            None => xmin::current_snapshot(source_pool).await?,
+        };
+        let ranges = backfill::plan_ranges(source_pool, &table.name, config).await?;
+        info!(table = %state_key, target = %target.name, ranges = ranges.len(), "planned backfill");
+        let mut tx = target_pool.begin().await?;
+        backfill::save_plan(&mut tx, &state_key, &ranges).await?;
+        match &cursor_condition {
+            Some(condition) => state::pin_cursor_condition(&mut *tx, &state_key, condition).await?,
+            None if cursor_columns.is_empty() => state::save_xmin_watermark(&mut *tx, &state_key, snapshot.xmin).await?,
+            // an empty table, the backfill's single range reads it as of one snapshot like an incremental run
+            None => {}
+        }
+        tx.commit().await?;
+    }
+
+    let select_list = select_list(source_pool, table).await?;
+    for range in backfill::pending_ranges(target_pool, &state_key).await? {
+        let predicate = logging::loggable_sql(table, &range.predicate);
+        info!(table = %state_key, target = %target.name, range = range.range_no, predicate = %predicate, "backfilling range");
+        let mut predicates = vec![range.predicate.clone()];
+        predicates.extend(table.filter.clone());
+        let query = format!("SELECT {} FROM {} {}", select_list, table.name, filter::where_clause(&predicates));
+        let what = format!("Table {} range {}", state_key, range.range_no);
+        let checkpoint = Checkpoint::BackfillRange(range.range_no);
+        if let Some((_, err)) = sync_table(run, table, &query, copy_format, &[target], checkpoint, &what).await?.pop() {
+            return Ok(Some(err));
+        }
+    }
+    info!(table = %state_key, target = %target.name, "backfill completed");
+    Ok(None)
+}
+
+// A source database of the run, `"sources"` in the config or POSTGRES_URL_SOURCE without an id
+struct Source {
+    id: Option<String>,
+    pool: PgPool,
+}
+
+// `settings` are the configured source settings, without read_only for the capture modes
+async fn connect_sources(config: &SyncConfig, settings: &SessionSettings) -> Result<Vec<Source>, Box<dyn Error>> {
+    if config.sources.is_empty() {
+        let postgres_url_source = env::var("POSTGRES_URL_SOURCE").unwrap_or_else(|_| "nothing here".to_string());
+        info!(url = %logging::redact_url(&postgres_url_source), "connecting to the source");
+        let source_ssl = postgres_url_source + "?sslmode=require";
+        let pool = pool_options(config).connect_with(session::connect_options(&source_ssl, settings)?).await?;
+        return Ok(vec![Source { id: None, pool }]);
+    }
+    let mut sources = Vec::with_capacity(config.sources.len());
+    for source in &config.sources {
+        let url = env::var(&source.url_env).map_err(|_| format!("Source {}: {} is not set", source.id, source.url_env))?;
+        info!(source = %source.id, url = %logging::redact_url(&url), "connecting to the source");
+        let pool = pool_options(config).connect_with(session::connect_options(&(url + "?sslmode=require"), settings)?).await?;
+        sources.push(Source { id: Some(source.id.clone()), pool });
+    }
+    Ok(sources)
+}
+
+// Copies a table's slices concurrently, each with its own connections and retries. Slices commit
+// on their own, so the targets need a primary key to take the slices again. The xmin watermark is
+// saved once all of them are in, for the targets that got every slice. Cursor tables pin the
+// extract's cursor condition first: the target rows of a finished slice would otherwise move the
+// cursor past the rows of one that failed. Returns the targets that failed a slice for good.
+async fn sync_slices<'t>(
+    run: &Run<'_>,
+    table: &TableConfig,
+    extract: &Extract,
+    slices: &[String],
+    copy_format: CopyFormat,
+    targets: &[&'t Target],
+    xmin_watermark: Option<u64>,
+) -> Result<Vec<(&'t Target, Box<dyn Error>)>, Box<dyn Error>> {
+    let name = run.state_key(&table.name);
+    if let Some(condition) = &extract.cursor_condition {
+        for target in targets {
+            state::pin_cursor_condition(&target.pool, &name, condition).await?;
+        }
+    }
+    let finished = Cell::new(0);
+    let copies = slices.iter().enumerate().map(|(i, slice)| {
+        let what = format!("Table {} slice {}/{}", name, i + 1, slices.len());
+        let query = extract.query(Some(slice));
+        let (name, finished) = (&name, &finished);
+        async move {
+            let result = sync_table(run, table, &query, copy_format, targets, Checkpoint::None, &what).await;
+            finished.set(finished.get() + 1);
+            let outcome = if matches!(&result, Ok(failed) if failed.is_empty()) { "done" } else { "failed" };
+            info!(table = %name, slice = i + 1, slices = slices.len(), finished = finished.get(), "slice {}", outcome);
+            result
+        }
+    });
+    let mut failed: Vec<(&Target, Box<dyn Error>)> = Vec::new();
+    let mut source_error = None;
+    for result in join_all(copies).await {
+        match result {
+            Ok(slice_failed) => {
+                for (target, err) in slice_failed {
+                    if !failed.iter().any(|(t, _)| t.name == target.name) {
+                        failed.push((target, err));
+                    }
+                }
+            }
             Err(err) => {
-                eprintln!("Error during streaming {:?}", err);
-                return Err(Box::new(err));
+                source_error.get_or_insert(err);
             }
         }
     }
-    // Finish the COPY operation on the target database
-    copy_in.finish().await?;
+    if let Some(err) = source_error {
+        return Err(err);
+    }
+    for target in targets.iter().filter(|target| !failed.iter().any(|(t, _)| t.name == target.name)) {
+        match xmin_watermark {
+            Some(watermark) => state::save_xmin_watermark(&target.pool, &name, watermark).await?,
+            None => state::clear_cursor_condition(&target.pool, &name).await?,
+        }
+    }
+    Ok(failed)
+}
 
+// Syncs every table of the config from the run's source to the active targets. A target that fails
+// is left out for the rest of the run (its watermarks stay where they were) and noted in `failures`.
+// Targets that can share one extract, and whether they upsert. Targets with a primary key upsert
+// the rows they already have from an extract covering the one furthest behind. Targets without one
+// are appended to with plain COPY, so each gets an extract from its own watermarks.
+async fn extract_groups<'t>(table_name: &str, targets: &[&'t Target]) -> Result<Vec<(Vec<&'t Target>, bool)>, Box<dyn Error>> {
+    let mut upserting = Vec::new();
+    let mut groups = Vec::new();
+    for &target in targets {
+        if catalog::primary_key_columns(&target.pool, table_name).await?.is_empty() {
+            groups.push((vec![target], false));
+        } else {
+            upserting.push(target);
+        }
+    }
+    if !upserting.is_empty() {
+        groups.insert(0, (upserting, true));
+    }
+    Ok(groups)
+}
+
+async fn sync_tables(run: &Run<'_>, active: &mut Vec<&Target>, failures: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    for table in &run.config.tables {
+        let span = info_span!("table", table = %run.state_key(&table.name));
+        sync_table_to_active(run, table, active, failures).instrument(span).await?;
+    }
+    Ok(())
+}
+
+// One table of the run, see sync_tables
+async fn sync_table_to_active(
+    run: &Run<'_>,
+    table: &TableConfig,
+    active: &mut Vec<&Target>,
+    failures: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let source_pool = run.source_pool;
+    let name = run.state_key(&table.name);
+    if let Some(row_filter) = &table.filter {
+        filter::validate(source_pool, &table.name, row_filter).await?;
+    }
+    Masker::check_column_types(source_pool, table).await?;
+    // One stream feeds every target, so binary only if all of them can take it
+    let mut copy_format = run.config.copy_format_for(table);
+    let columns = copied_columns(source_pool, table, run.source_id).await?;
+    for target in active.iter() {
+        copy_format = copy_format::choose_format(source_pool, &target.pool, &table.name, columns.as_deref(), copy_format).await?;
+    }
+    // Tables with a backfill configured are loaded range by range first, incremental runs start after
+    let mut failed = Vec::new();
+    let mut incremental = Vec::new();
+    for &target in active.iter() {
+        match &table.backfill {
+            Some(backfill_config) if !backfill::is_complete(&target.pool, &name).await? => {
+                let backfilled = backfill_table(run, table, backfill_config, copy_format, target).await;
+                if let Some(err) = backfilled.map_err(|err| session::explain(err, &name, "the source"))? {
+                    failed.push((target, err));
+                }
+            }
+            _ => incremental.push(target),
+        }
+    }
+    for (group, upsert) in extract_groups(&table.name, &incremental).await? {
+        match &table.parallel {
+            Some(parallel) if parallel.slices > 1 && upsert => {
+                // Slices read through one snapshot, the run's or one exported for the table
+                let table_snapshot = match run.snapshot {
+                    Some(_) => None,
+                    None => Some(ExportedSnapshot::export(source_pool).await?),
+                };
+                let slice_run = Run { snapshot: run.snapshot.or(table_snapshot.as_ref()), ..*run };
+                let synced = async {
+                    let run_snapshot = slice_run.snapshot.map(|s| s.xids);
+                    let (extract, xmin_watermark) = query_update(source_pool, &group, table, run.source_id, run_snapshot).await?;
+                    let slices = slice::plan_slices(source_pool, &table.name, parallel).await?;
+                    info!(table = %name, slices = slices.len(), "copying in slices");
+                    let synced = sync_slices(&slice_run, table, &extract, &slices, copy_format, &group, xmin_watermark).await;
+                    synced.map_err(|err| session::explain(err, &name, "the source"))
+                }
+                .await;
+                // Released whatever happened, the exporting transaction would stay open until exit
+                let released = match table_snapshot {
+                    Some(table_snapshot) => table_snapshot.release().await,
+                    None => Ok(()),
+                };
+                failed.extend(synced?);
+                released?;
+            }
+            parallel => {
+                if parallel.as_ref().is_some_and(|parallel| parallel.slices > 1) {
+                    info!(table = %name, target = %group[0].name, "no primary key to take slices again, copying in one stream");
+                }
+                let run_snapshot = run.snapshot.map(|s| s.xids);
+                let (extract, xmin_watermark) = query_update(source_pool, &group, table, run.source_id, run_snapshot).await?;
+                let what = format!("Table {}", name);
+                let checkpoint = xmin_watermark.map_or(Checkpoint::Cursor, Checkpoint::XminWatermark);
+                let synced = sync_table(run, table, &extract.query(None), copy_format, &group, checkpoint, &what).await;
+                failed.extend(synced.map_err(|err| session::explain(err, &name, "the source"))?);
+            }
+        }
+    }
+    for (target, err) in failed {
+        let err = session::explain(err, &name, &target.name);
+        active.retain(|t| t.name != target.name);
+        if active.is_empty() {
+            return Err(err);
+        }
+        error!(table = %name, target = %target.name, error = %err, "table failed, skipping the target for the rest of the run");
+        failures.push(format!("{} on {}: {}", name, target.name, err));
+    }
+    info!(table = %name, duration_ms = started.elapsed().as_millis() as u64, "table done");
+    Ok(())
+}
+
+// `verify [--json] [--since <value>] [--until <value>] [--checksum]`: row counts (or contents) of every table
+// on the sources and targets
+async fn verify_tables(sources: &[Source], targets: &[Target], config: &SyncConfig, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let options = verify::VerifyOptions::parse(args)?;
+    let target_pools: Vec<(&str, &PgPool)> = targets.iter().map(|t| (t.name.as_str(), &t.pool)).collect();
+    if options.checksum {
+        let mut results = Vec::new();
+        for source in sources {
+            results.extend(checksum::checksum_tables(&source.pool, source.id.as_deref(), &target_pools, &config.tables, &options).await?);
+        }
+        checksum::print_report(&results, options.json)?;
+        let mismatches = results.iter().filter(|r| !r.matches()).count();
+        if mismatches > 0 {
+            return Err(format!("{} of {} table checksum(s) differ", mismatches, results.len()).into());
+        }
+        return Ok(());
+    }
+    let mut counts = Vec::new();
+    for source in sources {
+        counts.extend(verify::count_tables(&source.pool, source.id.as_deref(), &target_pools, &config.tables, &options).await?);
+    }
+    verify::print_report(&counts, options.json)?;
+    let mismatches = counts.iter().filter(|c| !c.matches()).count();
+    if mismatches > 0 {
+        return Err(format!("{} of {} table count(s) differ", mismatches, counts.len()).into());
+    }
+    Ok(())
+}
+
+// `repair [--chunk-rows N] [--leaf-rows N] [--since <value>] [--until <value>]`: the key ranges
+// `verify --checksum` finds are copied again and replace what the target has in them
+async fn repair_tables(sources: &[Source], targets: &[Target], config: &SyncConfig, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let options = verify::VerifyOptions::parse(args)?;
+    for source in sources {
+        let run = Run { source_pool: &source.pool, source_id: source.id.as_deref(), config, snapshot: None, spool: None };
+        for table in &config.tables {
+            let name = run.state_key(&table.name);
+            for target in targets {
+                let result = checksum::checksum_table(&source.pool, run.source_id, &target.name, &target.pool, table, &options).await?;
+                if let Some(reason) = &result.skipped {
+                    info!(table = %name, target = %target.name, reason = %reason, "skipped");
+                    continue;
+                }
+                if result.matches() {
+                    info!(table = %name, target = %target.name, chunks = result.chunks, "chunks match, nothing to repair");
+                    continue;
+                }
+                let select_list = select_list(&source.pool, table).await?;
+                let columns = copied_columns(&source.pool, table, run.source_id).await?;
+                let copy_format = copy_format::choose_format(&source.pool, &target.pool, &table.name, columns.as_deref(), config.copy_format_for(table)).await?;
+                for difference in &result.differences {
+                    let mut predicates = vec![difference.predicate.clone()];
+                    predicates.extend(table.filter.clone());
+                    let extract = Extract {
+                        table_name: table.name.clone(),
+                        select_list: select_list.clone(),
+                        predicates,
+                        order_by: None,
+                        cursor_condition: None,
+                    };
+                    // Consolidated targets only lose rows of this source
+                    let mut target_conditions = vec![difference.predicate.clone()];
+                    target_conditions.extend(run.source_id.map(shard::source_condition));
+                    let condition = target_conditions.join(" AND ");
+                    let predicate = logging::loggable_sql(table, &difference.predicate);
+                    let what = format!("Repair of {} on {} where {}", name, target.name, predicate);
+                    info!(
+                        table = %name,
+                        target = %target.name,
+                        predicate = %predicate,
+                        source_rows = difference.source_rows,
+                        target_rows = difference.target_rows,
+                        "repairing range"
+                    );
+                    let checkpoint = Checkpoint::RepairRange(&condition);
+                    let failed = sync_table(&run, table, &extract.query(None), copy_format, &[target], checkpoint, &what).await?;
+                    if let Some((_, err)) = failed.into_iter().next() {
+                        return Err(session::explain(err, &name, &target.name));
+                    }
+                }
+                info!(table = %name, target = %target.name, ranges = result.differences.len(), "repaired");
+            }
+        }
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let postgres_url_source = env::var("POSTGRES_URL_SOURCE").unwrap_or_else(|_| "nothing here".to_string());
-    let postgres_url_target = env::var("POSTGRES_URL_TARGET").unwrap_or_else(|_| "nothing here".to_string());
+    let config = SyncConfig::from_env()?.with_column_excludes(&[]);
+    logging::init(&config.logging)?;
+    let args: Vec<String> = env::args().collect();
+    let command = args.get(1).cloned().unwrap_or_else(|| "sync".to_string());
+    let started = Instant::now();
+    let result = run(&config, &args).instrument(info_span!("run", command = %command)).await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if let Err(err) = result {
+        error!(command = %command, duration_ms, error = %err, "run failed");
+        std::process::exit(1);
+    }
+    info!(command = %command, duration_ms, "run finished");
+    Ok(())
+}
+
+async fn run(config: &SyncConfig, args: &[String]) -> Result<(), Box<dyn Error>> {
+    // cdc and trigger capture create slots, publications and triggers on the source
+    let mut source_settings = config.session.source.clone();
+    if matches!(args.get(1).map(String::as_str), Some("cdc" | "trigger")) {
+        source_settings.read_only = false;
+    }
+    // connect to the sources
+    let sources = connect_sources(config, &source_settings).await?;
+    // connect to the targets
+    let targets = connect_targets(config).await?;
+    // cdc and trigger capture read the first source and apply changes to the first target
+    let (source_pool, target_pool) = (&sources[0].pool, &targets[0].pool);
 
-    let source_ssl = postgres_url_source.to_owned() + "?sslmode=require";
-    let target_ssl = postgres_url_target.to_owned() + "?sslmode=require";
-    // connect to the source
-    let source_pool = PgPool::connect(source_ssl.as_str()).await?;
-    // connect to the target
-    let target_pool = PgPool::connect(target_ssl.as_str()).await?;
+    // `cdc` streams changes from a logical replication slot, `trigger install|uninstall|drain`
+    // manages the trigger based capture, anything else does the regular COPY sync
+    match (args.get(1).map(String::as_str), args.get(2).map(String::as_str)) {
+        (Some("cdc" | "trigger"), _) if !config.sources.is_empty() => {
+            return Err("cdc and trigger capture read a single source, they can't be used with \"sources\"".into())
+        }
+        (Some("cdc"), _) => return cdc::run(source_pool, target_pool, config).await,
+        (Some("trigger"), Some("install")) => return trigger_capture::install(source_pool, config).await,
+        (Some("trigger"), Some("uninstall")) => return trigger_capture::uninstall(source_pool, config).await,
+        (Some("trigger"), Some("drain") | None) => return trigger_capture::drain(source_pool, target_pool, config).await,
+        (Some("trigger"), Some(other)) => return Err(format!("Unknown trigger command {}", other).into()),
+        (Some("verify"), _) => return verify_tables(&sources, &targets, config, args.get(2..).unwrap_or_default()).await,
+        (Some("repair"), _) => return repair_tables(&sources, &targets, config, args.get(2..).unwrap_or_default()).await,
+        _ => {}
+    }
 
-    // List of tables to transfer check with them
-    // let tables = list_tables_create(&source_pool, &target_pool).await?;
+    for target in &targets {
+        state::ensure_state_table(&target.pool).await?;
+        backfill::ensure_backfill_table(&target.pool).await?;
+        // Consolidated tables need `_source_id` in their primary key before anything is merged
+        if !config.sources.is_empty() {
+            for table in &config.tables {
+                shard::prepare_target_table(&target.pool, &table.name).await?;
+            }
+        }
+        // Without a key the re-scanned rows would be appended again on every run
+        for table in config.tables.iter().filter(|table| table.lookback.is_some()) {
+            if catalog::primary_key_columns(&target.pool, &table.name).await?.is_empty() {
+                return Err(format!("Table {} has a lookback but no primary key on {} to upsert the re-copied rows", table.name, target.name).into());
+            }
+        }
+    }
 
-    let tables = vec!["table1"]; // or to get it from the function list_tables_create?
+    let spool = config.spool.as_ref().map(Spool::create).transpose()?;
+    let mut active: Vec<&Target> = targets.iter().collect();
+    let mut failures = Vec::new();
+    let synced = async {
+        for source in &sources {
+            // With consistent_snapshot every table of this run is read as of the same moment
+            let run_snapshot = if config.consistent_snapshot {
+                Some(ExportedSnapshot::export(&source.pool).await?)
+            } else {
+                None
+            };
+            let run = Run {
+                source_pool: &source.pool,
+                source_id: source.id.as_deref(),
+                config,
+                snapshot: run_snapshot.as_ref(),
+                spool: spool.as_ref(),
+            };
+            let synced = sync_tables(&run, &mut active, &mut failures).await;
+            let released = match run_snapshot {
+                Some(run_snapshot) => run_snapshot.release().await,
+                None => Ok(()),
+            };
+            synced?;
+            released?;
+        }
+        Ok::<_, Box<dyn Error>>(())
+    }
+    .await;
 
-    for table in tables {
-        let custom_query = query_update(&source_pool, &target_pool, table).await?;
-        transfer_table(&source_pool, &target_pool, table, &custom_query).await?;
+    // Files of a failed run are never loaded again either
+    if let Some(spool) = spool {
+        spool.cleanup().await?;
+    }
+    synced?;
+    if !failures.is_empty() {
+        return Err(format!("{} target(s) failed: {}", failures.len(), failures.join("; ")).into());
     }
 
     Ok(())
-}
\ No newline at end of file
+}
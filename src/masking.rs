@@ -0,0 +1,290 @@
+// Column masking for non-production targets. Rules work on the text representation of a value
+// so the same code masks COPY rows and the JSON documents of parse_date_jsonb.
+// hash, fake and shift_date are derived from HMAC-SHA256 of the value with the secret in
+// SYNC_MASKING_KEY: the output is stable between runs and tables (joins on masked keys still
+// work) but can't be reversed by hashing guessed values without the key. hash keeps the shape of
+// integer and uuid values so key columns keep their type; hash and fake are rejected on columns
+// their values don't fit.
+use crate::config::{MaskRule, TableConfig};
+use crate::transform::{ColumnTransform, CopyRow};
+use chrono::{Duration, NaiveDate};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// hex characters kept from the hash
+const HASH_LENGTH: usize = 32;
+// longest hash of a value in a text column, a hyphenated uuid
+const HASH_MAX_LENGTH: i32 = 36;
+// column types fake and hash produce values of, as named by format_type
+const TEXT_TYPES: &[&str] = &["text", "character varying", "character", "bpchar", "citext", "name"];
+const HASHABLE_TYPES: &[&str] = &["uuid", "bigint"];
+
+#[derive(Clone, Default)]
+pub struct Masker {
+    key: Option<Vec<u8>>,
+}
+
+// The key stays out of debug output, it ends up in errors and logs
+impl fmt::Debug for Masker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Masker").field("key", &self.key.as_ref().map(|_| "***")).finish()
+    }
+}
+
+impl Masker {
+    pub fn from_env() -> Self {
+        Masker { key: env::var("SYNC_MASKING_KEY").ok().filter(|k| !k.is_empty()).map(String::into_bytes) }
+    }
+
+    // Fail before copying anything when a keyed rule is configured without the key
+    pub fn check(&self, column: &str, rule: &MaskRule) -> Result<(), Box<dyn Error>> {
+        let keyed = matches!(rule, MaskRule::Hash | MaskRule::Fake | MaskRule::ShiftDate(_));
+        if keyed && self.key.is_none() {
+            return Err(format!("Masking rule {:?} of column {} needs SYNC_MASKING_KEY", rule, column).into());
+        }
+        Ok(())
+    }
+
+    // Fail before copying anything when hash or fake would produce values the target column can't
+    // take: another type, or text longer than a varchar(n)/char(n) allows
+    pub async fn check_column_types(pool: &PgPool, table: &TableConfig) -> Result<(), Box<dyn Error>> {
+        for (column, rule) in &table.masking {
+            if !matches!(rule, MaskRule::Hash | MaskRule::Fake) {
+                continue;
+            }
+            let row: Option<(String, i32)> = sqlx::query_as(
+                "SELECT format_type(atttypid, NULL), atttypmod FROM pg_attribute
+                 WHERE attrelid = $1::regclass AND attname = $2 AND attnum > 0 AND NOT attisdropped",
+            )
+            .bind(&table.name)
+            .bind(column)
+            .fetch_optional(pool)
+            .await?;
+            if let Some((data_type, typmod)) = row {
+                check_column_type(rule, &data_type, typmod)
+                    .map_err(|reason| format!("Masking rule {:?} of column {} of table {} {}", rule, column, table.name, reason))?;
+            }
+        }
+        Ok(())
+    }
+
+    // HMAC of the value, `round` extends the output for long values
+    fn digest(&self, value: &str, round: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let key = self.key.as_deref().ok_or("SYNC_MASKING_KEY is not set")?;
+        let mut mac = HmacSha256::new_from_slice(key)?;
+        mac.update(value.as_bytes());
+        if round > 0 {
+            mac.update(&round.to_be_bytes());
+        }
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    // Integers hash to a non-negative bigint and uuids to a uuid, anything else to hex text
+    fn hash(&self, value: &str) -> Result<String, Box<dyn Error>> {
+        let digest = self.digest(value, 0)?;
+        if value.parse::<i64>().is_ok() {
+            return Ok((u64::from_be_bytes(digest[..8].try_into()?) >> 1).to_string());
+        }
+        if Uuid::parse_str(value).is_ok() {
+            return Ok(Uuid::from_slice(&digest[..16])?.hyphenated().to_string());
+        }
+        Ok(hex::encode(digest)[..HASH_LENGTH].to_string())
+    }
+
+    fn fake(&self, value: &str) -> Result<String, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        let mut round = 0;
+        let mut faked = String::with_capacity(value.len());
+        for (i, c) in value.chars().enumerate() {
+            if i >= bytes.len() {
+                bytes.extend(self.digest(value, round)?);
+                round += 1;
+            }
+            let b = bytes[i];
+            let replacement = if c.is_ascii_digit() {
+                (b'0' + b % 10) as char
+            } else if c.is_uppercase() {
+                (b'A' + b % 26) as char
+            } else if c.is_alphabetic() {
+                (b'a' + b % 26) as char
+            } else {
+                c
+            };
+            faked.push(replacement);
+        }
+        Ok(faked)
+    }
+
+    // Shifts the leading YYYY-MM-DD and keeps the rest (time, offset, BC) as it was
+    fn shift_date(&self, value: &str, max_days: i64) -> Result<String, Box<dyn Error>> {
+        if value == "infinity" || value == "-infinity" {
+            return Ok(value.to_string());
+        }
+        let date = value
+            .get(..10)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+            // The value itself stays out of the error, it ends up in the logs
+            .ok_or("Can't shift a value that is not a date or timestamp")?;
+        let digest = self.digest(value, 0)?;
+        let n = u64::from_be_bytes(digest[..8].try_into()?);
+        let offset = (n % (2 * max_days.unsigned_abs() + 1)) as i64 - max_days.abs();
+        let shifted = date
+            .checked_add_signed(Duration::days(offset))
+            .ok_or("Date out of range after shifting")?;
+        Ok(format!("{}{}", shifted.format("%Y-%m-%d"), &value[10..]))
+    }
+
+    pub fn mask(&self, rule: &MaskRule, value: Option<&str>) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(value) = value else {
+            // NULL stays NULL except for a fixed value
+            return Ok(match rule {
+                MaskRule::Fixed(fixed) => Some(fixed.clone()),
+                _ => None,
+            });
+        };
+        let masked = match rule {
+            MaskRule::Null => None,
+            MaskRule::Fixed(fixed) => Some(fixed.clone()),
+            MaskRule::Hash => Some(self.hash(value)?),
+            MaskRule::Fake => Some(self.fake(value)?),
+            MaskRule::Truncate(length) => Some(value.chars().take(*length).collect()),
+            MaskRule::ShiftDate(max_days) => Some(self.shift_date(value, *max_days)?),
+        };
+        Ok(masked)
+    }
+
+    // Masks the configured keys of a JSON row; non-string values are masked by their JSON text and
+    // stay numbers or booleans if the masked text still is one, strings otherwise
+    pub fn mask_json(&self, rules: &BTreeMap<String, MaskRule>, row: &mut Value) -> Result<(), Box<dyn Error>> {
+        let Value::Object(map) = row else {
+            return Ok(());
+        };
+        for (column, rule) in rules {
+            if let Some(value) = map.get_mut(column) {
+                let (text, is_string) = match &*value {
+                    Value::Null => (None, false),
+                    Value::String(s) => (Some(s.clone()), true),
+                    other => (Some(other.to_string()), false),
+                };
+                *value = match self.mask(rule, text.as_deref())? {
+                    Some(masked) if !is_string => match serde_json::from_str::<Value>(&masked) {
+                        Ok(typed @ (Value::Number(_) | Value::Bool(_))) => typed,
+                        _ => Value::String(masked),
+                    },
+                    Some(masked) => Value::String(masked),
+                    None => Value::Null,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    pub fn column_mask(&self, rule: MaskRule) -> ColumnMask {
+        ColumnMask { masker: self.clone(), rule }
+    }
+}
+
+// Whether a hash or fake rule's values fit a column of `data_type` (format_type without the
+// modifier) and `typmod` (length + 4 for varchar(n)/char(n), -1 without a length)
+fn check_column_type(rule: &MaskRule, data_type: &str, typmod: i32) -> Result<(), String> {
+    let text = TEXT_TYPES.contains(&data_type);
+    match rule {
+        MaskRule::Hash if !text && !HASHABLE_TYPES.contains(&data_type) => {
+            Err(format!("can't keep its type {}, use a text, uuid or bigint column or another rule", data_type))
+        }
+        MaskRule::Hash if text && typmod >= 0 && typmod - 4 < HASH_MAX_LENGTH => Err(format!(
+            "needs up to {} characters, the column is {}({})",
+            HASH_MAX_LENGTH,
+            data_type,
+            typmod - 4
+        )),
+        // fake keeps the length but not the format of dates, numbers or uuids
+        MaskRule::Fake if !text => Err(format!("can't keep its type {}, use a text column or another rule", data_type)),
+        _ => Ok(()),
+    }
+}
+
+// A masking rule as a step of the COPY transformation pipeline
+pub struct ColumnMask {
+    masker: Masker,
+    rule: MaskRule,
+}
+
+impl ColumnTransform for ColumnMask {
+    fn apply(&self, value: Option<String>, _row: &CopyRow, _header: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+        self.masker.mask(&self.rule, value.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn masker() -> Masker {
+        Masker { key: Some(b"secret".to_vec()) }
+    }
+
+    #[test]
+    fn debug_hides_the_key() {
+        let printed = format!("{:?}", masker());
+        assert!(!printed.contains("secret") && !printed.contains("115"), "{}", printed);
+        assert_eq!(format!("{:?}", Masker::default()), "Masker { key: None }");
+    }
+
+    #[test]
+    fn hash_keeps_integers_and_uuids() {
+        let masker = masker();
+        let integer = masker.mask(&MaskRule::Hash, Some("42")).unwrap().unwrap();
+        assert!(integer.parse::<i64>().unwrap() >= 0);
+        let uuid = masker.mask(&MaskRule::Hash, Some("8c4b0a4e-5e8b-4c39-9d1e-3f1f2a7b6c5d")).unwrap().unwrap();
+        assert!(Uuid::parse_str(&uuid).is_ok());
+        let text = masker.mask(&MaskRule::Hash, Some("a@b.c")).unwrap().unwrap();
+        assert_eq!(text.len(), HASH_LENGTH);
+        assert_eq!(masker.mask(&MaskRule::Hash, Some("42")).unwrap().unwrap(), integer);
+    }
+
+    #[test]
+    fn hash_and_fake_need_fitting_columns() {
+        assert!(check_column_type(&MaskRule::Hash, "text", -1).is_ok());
+        assert!(check_column_type(&MaskRule::Hash, "uuid", -1).is_ok());
+        assert!(check_column_type(&MaskRule::Hash, "bigint", -1).is_ok());
+        assert!(check_column_type(&MaskRule::Hash, "integer", -1).is_err());
+        // varchar(n) and char(n) store n + 4 as the modifier
+        assert!(check_column_type(&MaskRule::Hash, "character varying", 40).is_ok());
+        assert!(check_column_type(&MaskRule::Hash, "character varying", 24).is_err());
+        assert!(check_column_type(&MaskRule::Hash, "character", 14).is_err());
+        assert!(check_column_type(&MaskRule::Fake, "character varying", 14).is_ok());
+        assert!(check_column_type(&MaskRule::Fake, "date", -1).is_err());
+        assert!(check_column_type(&MaskRule::Fake, "numeric", 655366).is_err());
+        assert!(check_column_type(&MaskRule::Fake, "uuid", -1).is_err());
+        assert!(check_column_type(&MaskRule::Null, "date", -1).is_ok());
+    }
+
+    #[test]
+    fn mask_json_keeps_value_types() {
+        let rules = BTreeMap::from([
+            ("id".to_string(), MaskRule::Hash),
+            ("score".to_string(), MaskRule::Fake),
+            ("email".to_string(), MaskRule::Hash),
+            ("note".to_string(), MaskRule::Null),
+            ("flag".to_string(), MaskRule::Fixed("zero".to_string())),
+        ]);
+        let mut row = json!({ "id": 7, "score": 12.5, "email": "x@y.z", "note": "n", "flag": true });
+        masker().mask_json(&rules, &mut row).unwrap();
+        assert!(row["id"].is_u64());
+        assert!(row["score"].is_f64());
+        assert!(row["email"].is_string());
+        assert!(row["note"].is_null());
+        assert_eq!(row["flag"], json!("zero"));
+    }
+}
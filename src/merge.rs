@@ -0,0 +1,143 @@
+// Loading through a staging table: COPY has no ON CONFLICT, so rows are copied into a temporary
+// `<table>_sqlx` table on the same connection and then upserted into the physical table by its
+// primary key. Re-copying rows (lookback windows, xmin re-scans, retries) is therefore harmless.
+use crate::sql::{column_list, quote_ident};
+use sqlx::PgConnection;
+use std::error::Error;
+
+// Temporary tables can't be schema-qualified, so only the table part of the name is used
+pub fn staging_table_name(table_name: &str) -> String {
+    let table = table_name.rsplit('.').next().unwrap_or(table_name);
+    quote_ident(&format!("{}_sqlx", table))
+}
+
+// With `columns` only those are staged, without the constraints of the table: a NOT NULL column
+// that isn't copied would fail the COPY otherwise. The upsert leaves the others as they are.
+pub async fn create_staging_table(conn: &mut PgConnection, table_name: &str, columns: Option<&[String]>) -> Result<String, Box<dyn Error>> {
+    let staging = staging_table_name(table_name);
+    let definition = match columns {
+        Some(columns) => format!("AS SELECT {} FROM {} WITH NO DATA", column_list(columns), table_name),
+        None => format!("(LIKE {} INCLUDING DEFAULTS)", table_name),
+    };
+    sqlx::query(&format!("CREATE TEMPORARY TABLE IF NOT EXISTS {} {}", staging, definition))
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("TRUNCATE {}", staging)).execute(&mut *conn).await?;
+    Ok(staging)
+}
+
+pub fn upsert_statement(table_name: &str, staging: &str, columns: &[String], primary_key: &[String]) -> String {
+    let column_list = columns.iter().map(|c| quote_ident(c)).collect::<Vec<_>>().join(", ");
+    let key_list = primary_key.iter().map(|c| quote_ident(c)).collect::<Vec<_>>().join(", ");
+    let updates = columns
+        .iter()
+        .filter(|c| !primary_key.contains(c))
+        .map(|c| format!("{} = EXCLUDED.{}", quote_ident(c), quote_ident(c)))
+        .collect::<Vec<_>>();
+    let action = if updates.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET {}", updates.join(", "))
+    };
+    format!(
+        "INSERT INTO {} ({}) SELECT {} FROM {} ON CONFLICT ({}) {}",
+        table_name, column_list, column_list, staging, key_list, action
+    )
+}
+
+// For a staging table with only some of the columns: INSERT ... ON CONFLICT checks the proposed
+// row's NOT NULL constraints before it finds the conflict, so rows that exist are updated first
+// and only the others inserted
+pub fn update_and_insert_statements(table_name: &str, staging: &str, columns: &[String], primary_key: &[String]) -> Vec<String> {
+    let key_match = format!(
+        "({}) = ({})",
+        primary_key.iter().map(|c| format!("t.{}", quote_ident(c))).collect::<Vec<_>>().join(", "),
+        primary_key.iter().map(|c| format!("s.{}", quote_ident(c))).collect::<Vec<_>>().join(", ")
+    );
+    let updates = columns
+        .iter()
+        .filter(|c| !primary_key.contains(c))
+        .map(|c| format!("{} = s.{}", quote_ident(c), quote_ident(c)))
+        .collect::<Vec<_>>();
+    let mut statements = Vec::with_capacity(2);
+    if !updates.is_empty() {
+        statements.push(format!("UPDATE {} AS t SET {} FROM {} AS s WHERE {}", table_name, updates.join(", "), staging, key_match));
+    }
+    let column_list = column_list(columns);
+    statements.push(format!(
+        "INSERT INTO {} ({}) SELECT {} FROM {} AS s WHERE NOT EXISTS (SELECT 1 FROM {} AS t WHERE {})",
+        table_name, column_list, column_list, staging, table_name, key_match
+    ));
+    statements
+}
+
+// Upsert everything from the staging table and drop it, returns the number of rows merged.
+// `whole_rows` when the staging table has every column of the table.
+pub async fn merge_staging_table(
+    conn: &mut PgConnection,
+    table_name: &str,
+    staging: &str,
+    columns: &[String],
+    primary_key: &[String],
+    whole_rows: bool,
+) -> Result<u64, Box<dyn Error>> {
+    let statements = match whole_rows {
+        true => vec![upsert_statement(table_name, staging, columns, primary_key)],
+        false => update_and_insert_statements(table_name, staging, columns, primary_key),
+    };
+    let mut merged = 0;
+    for statement in &statements {
+        merged += sqlx::query(statement).execute(&mut *conn).await?.rows_affected();
+    }
+    sqlx::query(&format!("DROP TABLE {}", staging)).execute(&mut *conn).await?;
+    Ok(merged)
+}
+
+// Deletes the target rows matching `condition` whose key isn't in the staging table, for ranges
+// that were copied whole. Runs before the merge, which drops the staging table.
+pub async fn delete_missing(
+    conn: &mut PgConnection,
+    table_name: &str,
+    staging: &str,
+    primary_key: &[String],
+    condition: &str,
+) -> Result<u64, Box<dyn Error>> {
+    let target_key = primary_key.iter().map(|c| format!("t.{}", quote_ident(c))).collect::<Vec<_>>().join(", ");
+    let staging_key = primary_key.iter().map(|c| format!("s.{}", quote_ident(c))).collect::<Vec<_>>().join(", ");
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM {} AS t WHERE ({}) AND NOT EXISTS (SELECT 1 FROM {} AS s WHERE ({}) = ({}))",
+        table_name, condition, staging, staging_key, target_key
+    ))
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(columns: &[&str]) -> Vec<String> {
+        columns.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn partial_rows_are_updated_then_inserted() {
+        let statements = update_and_insert_statements("t1", "\"t1_sqlx\"", &names(&["id", "v"]), &names(&["id"]));
+        assert_eq!(
+            statements,
+            vec![
+                "UPDATE t1 AS t SET \"v\" = s.\"v\" FROM \"t1_sqlx\" AS s WHERE (t.\"id\") = (s.\"id\")",
+                "INSERT INTO t1 (\"id\", \"v\") SELECT \"id\", \"v\" FROM \"t1_sqlx\" AS s WHERE NOT EXISTS (SELECT 1 FROM t1 AS t WHERE (t.\"id\") = (s.\"id\"))",
+            ]
+        );
+    }
+
+    #[test]
+    fn key_only_rows_are_just_inserted() {
+        let statements = update_and_insert_statements("t1", "s", &names(&["a", "b"]), &names(&["a", "b"]));
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0].starts_with("INSERT INTO t1"));
+    }
+}
@@ -0,0 +1,77 @@
+// Channel between the COPY OUT reader and the COPY IN writer of a table, bounded by the bytes in
+// flight rather than the number of chunks (chunk sizes vary a lot with transforms). A chunk's
+// bytes count until the writer is done with it. Dropping either end closes the channel: the
+// reader's send fails once the writer is gone and the writer's recv ends once the reader is.
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+
+pub fn channel(capacity_bytes: usize) -> (Sender, Receiver) {
+    let capacity = capacity_bytes.clamp(1, Semaphore::MAX_PERMITS.min(u32::MAX as usize));
+    let budget = Arc::new(Semaphore::new(capacity));
+    let (tx, rx) = mpsc::unbounded_channel();
+    (Sender { tx, budget: budget.clone(), capacity }, Receiver { rx, budget })
+}
+
+pub struct Sender {
+    tx: mpsc::UnboundedSender<Chunk>,
+    budget: Arc<Semaphore>,
+    capacity: usize,
+}
+
+// The receiver is gone, nothing more will be written
+#[derive(Debug)]
+pub struct Closed;
+
+impl Sender {
+    // Waits until the chunk fits in the buffer, a chunk larger than the whole buffer waits for it to drain
+    pub async fn send(&self, data: Bytes) -> Result<(), Closed> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let permits = data.len().min(self.capacity) as u32;
+        self.budget.acquire_many(permits).await.map_err(|_| Closed)?.forget();
+        let chunk = Chunk { data, permits, budget: self.budget.clone() };
+        self.tx.send(chunk).map_err(|_| Closed)
+    }
+}
+
+pub struct Receiver {
+    rx: mpsc::UnboundedReceiver<Chunk>,
+    budget: Arc<Semaphore>,
+}
+
+impl Receiver {
+    // None once the sender is dropped and everything sent has been received
+    pub async fn recv(&mut self) -> Option<Chunk> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Receiver {
+    // Wakes a sender waiting for room that will never come
+    fn drop(&mut self) {
+        self.budget.close();
+    }
+}
+
+// Data taken from the channel, its bytes are released when it's dropped
+pub struct Chunk {
+    data: Bytes,
+    permits: u32,
+    budget: Arc<Semaphore>,
+}
+
+impl std::ops::Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.budget.add_permits(self.permits as usize);
+    }
+}
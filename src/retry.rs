@@ -0,0 +1,85 @@
+// Retries of a table or backfill range after transient failures: dropped connections, pool
+// timeouts, serialization failures, deadlocks and server shutdowns/restarts. Anything else
+// (bad SQL, constraint violations, config errors) fails right away. Delays grow exponentially
+// with full jitter, bounded by the attempt count and a total time budget.
+use crate::config::RetryConfig;
+use rand::Rng;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// serialization_failure, deadlock_detected, lock_not_available (lock_timeout), admin_shutdown,
+// crash_shutdown, cannot_connect_now, too_many_connections; the whole 08 class is connection exceptions
+const TRANSIENT_SQLSTATES: [&str; 7] = ["40001", "40P01", "55P03", "57P01", "57P02", "57P03", "53300"];
+
+fn is_transient_io(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::NotConnected
+    )
+}
+
+pub fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(error) = error.downcast_ref::<sqlx::Error>() {
+            return match error {
+                sqlx::Error::Io(error) => is_transient_io(error),
+                sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+                sqlx::Error::Database(error) => error
+                    .code()
+                    .is_some_and(|code| code.starts_with("08") || TRANSIENT_SQLSTATES.contains(&code.as_ref())),
+                _ => false,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return is_transient_io(error);
+        }
+        current = error.source();
+    }
+    false
+}
+
+// Attempt counter and time budget of one retried operation, the caller's loop asks it whether to go on
+pub struct Backoff<'a> {
+    config: &'a RetryConfig,
+    started: Instant,
+    attempt: u32,
+}
+
+impl<'a> Backoff<'a> {
+    pub fn new(config: &'a RetryConfig) -> Self {
+        Backoff { config, started: Instant::now(), attempt: 1 }
+    }
+
+    fn delay(&self) -> Duration {
+        let exponential = self.config.initial_backoff_ms.saturating_mul(1u64 << (self.attempt - 1).min(20));
+        let capped = exponential.min(self.config.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=capped))
+    }
+
+    // Sleeps before the next attempt after `error`, false if the error is permanent or the
+    // attempts/time budget are used up
+    pub async fn retry(&mut self, what: &str, error: &(dyn Error + 'static)) -> bool {
+        if !is_transient(error) {
+            return false;
+        }
+        let delay = self.delay();
+        let budget = Duration::from_secs(self.config.max_elapsed_secs);
+        if self.attempt >= self.config.max_attempts || self.started.elapsed() + delay > budget {
+            warn!(attempts = self.attempt, elapsed = ?self.started.elapsed(), "{}: giving up", what);
+            return false;
+        }
+        warn!(attempt = self.attempt, delay = ?delay, error = %error, "{}: transient error, retrying", what);
+        tokio::time::sleep(delay).await;
+        self.attempt += 1;
+        true
+    }
+}
@@ -0,0 +1,107 @@
+// Session guardrails set at connection startup (as `-c` options, so they hold for every pooled
+// connection and every mode): statement/lock/idle-in-transaction timeouts, application_name and
+// read-only transactions on the source. Errors caused by one of the timeouts are reported with
+// the setting that was hit.
+use crate::config::SessionSettings;
+use sqlx::postgres::PgConnectOptions;
+use std::error::Error;
+use std::str::FromStr;
+
+const DEFAULT_APPLICATION_NAME: &str = "postgres_data_sync";
+
+// Spaces and backslashes have to be escaped inside the startup options string
+fn escape_option(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(' ', "\\ ")
+}
+
+pub fn connect_options(url: &str, settings: &SessionSettings) -> Result<PgConnectOptions, Box<dyn Error>> {
+    let mut options = Vec::new();
+    if let Some(timeout) = &settings.statement_timeout {
+        options.push(("statement_timeout", escape_option(timeout)));
+    }
+    if let Some(timeout) = &settings.lock_timeout {
+        options.push(("lock_timeout", escape_option(timeout)));
+    }
+    if let Some(timeout) = &settings.idle_in_transaction_session_timeout {
+        options.push(("idle_in_transaction_session_timeout", escape_option(timeout)));
+    }
+    if settings.read_only {
+        options.push(("default_transaction_read_only", "on".to_string()));
+    }
+    let application_name = settings.application_name.as_deref().unwrap_or(DEFAULT_APPLICATION_NAME);
+    let mut connect_options = PgConnectOptions::from_str(url)?.application_name(application_name);
+    if !options.is_empty() {
+        connect_options = connect_options.options(options);
+    }
+    Ok(connect_options)
+}
+
+// The guardrail behind a database error's SQLSTATE and message
+fn setting_for(code: Option<&str>, message: &str) -> Option<&'static str> {
+    match code {
+        // query_canceled is also raised by pg_cancel_backend
+        Some("57014") if message.contains("statement timeout") => Some("statement_timeout"),
+        Some("55P03") if message.contains("lock timeout") => Some("lock_timeout"),
+        Some("25P03") => Some("idle_in_transaction_session_timeout"),
+        Some("25006") => Some("default_transaction_read_only"),
+        _ => None,
+    }
+}
+
+// The guardrail behind an error, None if it wasn't caused by one
+fn violated_setting(error: &(dyn Error + 'static)) -> Option<&'static str> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(sqlx::Error::Database(error)) = error.downcast_ref::<sqlx::Error>() {
+            return setting_for(error.code().as_deref(), error.message());
+        }
+        current = error.source();
+    }
+    None
+}
+
+// Names the table, the side and the setting for errors caused by a guardrail, other errors are returned as they are
+pub fn explain(error: Box<dyn Error>, table_name: &str, side: &str) -> Box<dyn Error> {
+    match violated_setting(error.as_ref()) {
+        Some(setting) => format!("Table {} hit {} on {}: {}", table_name, setting, side, error).into(),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_spaces_and_backslashes() {
+        assert_eq!(escape_option("30s"), "30s");
+        assert_eq!(escape_option("5 min"), "5\\ min");
+        assert_eq!(escape_option("a\\b c"), "a\\\\b\\ c");
+    }
+
+    #[test]
+    fn maps_sqlstates_to_settings() {
+        assert_eq!(setting_for(Some("57014"), "canceling statement due to statement timeout"), Some("statement_timeout"));
+        assert_eq!(setting_for(Some("57014"), "canceling statement due to user request"), None);
+        assert_eq!(setting_for(Some("55P03"), "canceling statement due to lock timeout"), Some("lock_timeout"));
+        assert_eq!(setting_for(Some("55P03"), "could not obtain lock on row"), None);
+        assert_eq!(
+            setting_for(Some("25P03"), "terminating connection due to idle-in-transaction timeout"),
+            Some("idle_in_transaction_session_timeout")
+        );
+        assert_eq!(
+            setting_for(Some("25006"), "cannot execute INSERT in a read-only transaction"),
+            Some("default_transaction_read_only")
+        );
+        assert_eq!(setting_for(Some("23505"), "duplicate key value"), None);
+        assert_eq!(setting_for(None, "statement timeout"), None);
+    }
+
+    #[test]
+    fn other_errors_are_returned_as_they_are() {
+        let error = explain("connection refused".into(), "t1", "the source");
+        assert_eq!(error.to_string(), "connection refused");
+        let error = explain(Box::new(sqlx::Error::PoolTimedOut), "t1", "the source");
+        assert!(matches!(error.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::PoolTimedOut)));
+    }
+}
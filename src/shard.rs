@@ -0,0 +1,92 @@
+// Fan-in from several source databases (one per shard) into one target. Target tables get a
+// `_source_id` column that is part of their primary key, so rows with the same key from different
+// shards don't collide, and every row is loaded with the id of the source it came from. Watermarks
+// and backfill plans are kept per source under `<table>@<source id>` in the state tables.
+use crate::catalog::primary_key_columns;
+use crate::sql::{column_list, quote_ident, quote_literal};
+use sqlx::{PgConnection, PgPool};
+use std::error::Error;
+use tracing::info;
+
+pub const SOURCE_ID_COLUMN: &str = "_source_id";
+
+// Key of a table's watermarks and backfill plan in transform.sync_state/sync_backfill_ranges
+pub fn state_key(table_name: &str, source_id: Option<&str>) -> String {
+    match source_id {
+        Some(source_id) => format!("{}@{}", table_name, source_id),
+        None => table_name.to_string(),
+    }
+}
+
+// Target rows of one source, for watermarks taken from the target's own data
+pub fn source_condition(source_id: &str) -> String {
+    format!("{} = {}", quote_ident(SOURCE_ID_COLUMN), quote_literal(source_id))
+}
+
+// Adds `_source_id` to the target table and moves it into the primary key. Rows already in the
+// table get an empty source id. Tables whose key already has it are left alone, tables without a
+// primary key or with foreign keys referencing it can't be merged per source.
+pub async fn prepare_target_table(target_pool: &PgPool, table_name: &str) -> Result<(), Box<dyn Error>> {
+    let primary_key = primary_key_columns(target_pool, table_name).await?;
+    if primary_key.is_empty() {
+        return Err(format!("Table {} has no primary key on the target, it can't be loaded from several sources", table_name).into());
+    }
+    if primary_key.iter().any(|column| column == SOURCE_ID_COLUMN) {
+        return Ok(());
+    }
+    // The key can't be replaced while other tables reference it
+    let referencing: Vec<String> = sqlx::query_scalar(
+        "SELECT format('%s (%s)', conrelid::regclass, conname) FROM pg_constraint
+         WHERE confrelid = $1::regclass AND contype = 'f' ORDER BY 1",
+    )
+    .bind(table_name)
+    .fetch_all(target_pool)
+    .await?;
+    if !referencing.is_empty() {
+        return Err(format!(
+            "Table {} is referenced by foreign keys on the target ({}), {} can't be added to its primary key",
+            table_name,
+            referencing.join(", "),
+            SOURCE_ID_COLUMN
+        )
+        .into());
+    }
+    let constraint: String = sqlx::query_scalar("SELECT conname::text FROM pg_constraint WHERE conrelid = $1::regclass AND contype = 'p'")
+        .bind(table_name)
+        .fetch_one(target_pool)
+        .await?;
+    let mut key = vec![SOURCE_ID_COLUMN.to_string()];
+    key.extend(primary_key);
+    let mut tx = target_pool.begin().await?;
+    sqlx::query(&format!(
+        "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} text NOT NULL DEFAULT ''",
+        table_name,
+        quote_ident(SOURCE_ID_COLUMN)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "ALTER TABLE {} DROP CONSTRAINT {}, ADD PRIMARY KEY ({})",
+        table_name,
+        quote_ident(&constraint),
+        column_list(&key)
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!(table = %table_name, primary_key = %column_list(&key), "added {} to the primary key", SOURCE_ID_COLUMN);
+    Ok(())
+}
+
+// Rows copied into the staging table get the source's id without it being part of the COPY
+pub async fn set_staging_source_id(conn: &mut PgConnection, staging: &str, source_id: &str) -> Result<(), Box<dyn Error>> {
+    sqlx::query(&format!(
+        "ALTER TABLE {} ALTER COLUMN {} SET DEFAULT {}",
+        staging,
+        quote_ident(SOURCE_ID_COLUMN),
+        quote_literal(source_id)
+    ))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// Intra-table parallelism: one table's extraction split into N slices that are copied
+// concurrently, each on its own source and target connections. Slices are primary key ranges cut
+// at the pg_stats histogram bounds (even by row count as of the last ANALYZE), ctid page ranges
+// (even by size, no key needed) or hash(primary key) % N (even, but every slice scans the table).
+use crate::backfill::split_ranges;
+use crate::catalog::primary_key_columns;
+use crate::config::{ParallelConfig, SliceBy};
+use crate::sql::{column_list, quote_ident, quote_literal};
+use sqlx::{PgPool, Row};
+use std::error::Error;
+use tracing::info;
+
+// Range predicates for a single column primary key from its histogram, None without statistics
+async fn histogram_slices(source_pool: &PgPool, table_name: &str, slices: usize) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let key = primary_key_columns(source_pool, table_name).await?;
+    let [column] = key.as_slice() else {
+        return Ok(None);
+    };
+    let row = sqlx::query(
+        "SELECT s.histogram_bounds::text::text[] AS bounds, format_type(a.atttypid, a.atttypmod) AS data_type
+         FROM pg_attribute a
+         JOIN pg_class c ON c.oid = a.attrelid
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         LEFT JOIN pg_stats s ON s.schemaname = n.nspname AND s.tablename = c.relname AND s.attname = a.attname
+         WHERE a.attrelid = $1::regclass AND a.attname = $2",
+    )
+    .bind(table_name)
+    .bind(column)
+    .fetch_one(source_pool)
+    .await?;
+    let bounds: Option<Vec<String>> = row.try_get("bounds")?;
+    let data_type: String = row.try_get("data_type")?;
+    let Some(bounds) = bounds.filter(|b| b.len() >= 2) else {
+        return Ok(None);
+    };
+    // N - 1 cut points spread evenly over the bounds, each bucket holds about as many rows
+    let mut cuts: Vec<&String> = (1..slices).map(|i| &bounds[i * (bounds.len() - 1) / slices]).collect();
+    cuts.dedup();
+    let column = quote_ident(column);
+    let bound = |value: &str| format!("{}::{}", quote_literal(value), data_type);
+    let mut predicates = Vec::with_capacity(cuts.len() + 1);
+    for (i, cut) in cuts.iter().enumerate() {
+        match i.checked_sub(1).map(|previous| cuts[previous]) {
+            None => predicates.push(format!("{} < {}", column, bound(cut))),
+            Some(lower) => predicates.push(format!("{} >= {} AND {} < {}", column, bound(lower), column, bound(cut))),
+        }
+    }
+    match cuts.last() {
+        Some(last) => predicates.push(format!("{} >= {}", column, bound(last))),
+        None => predicates.push("TRUE".to_string()),
+    }
+    Ok(Some(predicates))
+}
+
+async fn ctid_slices(source_pool: &PgPool, table_name: &str, slices: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let pages: i64 = sqlx::query_scalar("SELECT pg_relation_size($1::regclass) / current_setting('block_size')::bigint")
+        .bind(table_name)
+        .fetch_one(source_pool)
+        .await?;
+    let chunk = (pages + slices as i64 - 1) / slices as i64;
+    let ranges = split_ranges("ctid", 0, pages - 1, chunk.max(1), |page| format!("'({},0)'::tid", page));
+    Ok(ranges.into_iter().map(|range| range.predicate).collect())
+}
+
+// Hash of the primary key (of the row's ctid without one) modulo N
+async fn hash_slices(source_pool: &PgPool, table_name: &str, slices: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let key = primary_key_columns(source_pool, table_name).await?;
+    let hashed = if key.is_empty() { "ctid".to_string() } else { column_list(&key) };
+    Ok((0..slices)
+        .map(|i| format!("mod(abs(hashtext(ROW({})::text)::bigint), {}) = {}", hashed, slices, i))
+        .collect())
+}
+
+// Predicates that together select every row of the table exactly once
+pub async fn plan_slices(source_pool: &PgPool, table_name: &str, config: &ParallelConfig) -> Result<Vec<String>, Box<dyn Error>> {
+    let slices = config.slices.max(1);
+    if slices == 1 {
+        return Ok(vec!["TRUE".to_string()]);
+    }
+    match config.split_by {
+        SliceBy::Histogram => match histogram_slices(source_pool, table_name, slices).await? {
+            Some(predicates) => Ok(predicates),
+            None => {
+                info!(table = %table_name, "no single column primary key with statistics, slicing by hash");
+                hash_slices(source_pool, table_name, slices).await
+            }
+        },
+        SliceBy::Ctid => ctid_slices(source_pool, table_name, slices).await,
+        SliceBy::Hash => hash_slices(source_pool, table_name, slices).await,
+    }
+}
@@ -0,0 +1,51 @@
+// One source snapshot for a whole run. A REPEATABLE READ transaction exports it with
+// pg_export_snapshot() and stays open until every table is copied; each COPY OUT (on whatever
+// connection or worker) imports it with SET TRANSACTION SNAPSHOT, so parent/child tables synced
+// in the same run are consistent with each other. Keep in mind the open transaction holds back
+// vacuum on the source for the duration of the run.
+use crate::sql::quote_literal;
+use crate::xmin::{self, SourceSnapshot};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use std::error::Error;
+use tracing::info;
+
+pub struct ExportedSnapshot {
+    tx: Transaction<'static, Postgres>,
+    pub id: String,
+    // xid bounds of the exported snapshot, the xmin watermark for tables read through it
+    pub xids: SourceSnapshot,
+}
+
+impl ExportedSnapshot {
+    pub async fn export(source_pool: &PgPool) -> Result<ExportedSnapshot, Box<dyn Error>> {
+        let mut tx = source_pool.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+        // The transaction sits idle until the end of the run, the session guardrails would end it
+        // and every later import with it
+        sqlx::query("SET LOCAL idle_in_transaction_session_timeout = 0").execute(&mut *tx).await?;
+        sqlx::query("SET LOCAL statement_timeout = 0").execute(&mut *tx).await?;
+        let id: String = sqlx::query_scalar("SELECT pg_export_snapshot()").fetch_one(&mut *tx).await?;
+        let xids = xmin::current_snapshot(&mut *tx).await?;
+        info!(snapshot = %id, xmin = xids.xmin, "exported source snapshot");
+        Ok(ExportedSnapshot { tx, id, xids })
+    }
+
+    // End the exporting transaction once all tables have been read
+    pub async fn release(self) -> Result<(), Box<dyn Error>> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+// Has to be the first thing run in the importing transaction
+pub async fn import(conn: &mut PgConnection, snapshot_id: &str) -> Result<(), Box<dyn Error>> {
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("SET TRANSACTION SNAPSHOT {}", quote_literal(snapshot_id)))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+// Local spool files: with `"spool"` configured COPY OUT is written to a compressed file first, the
+// source transaction ends as soon as the data is extracted and the target is loaded from the file.
+// Files are named by run, table and extraction query (which holds the watermarks or backfill
+// range), so a retry or another target of the same run loads the existing file instead of reading
+// the source again. A later run never loads them: the data would be as old as the run that wrote
+// it. A file is removed once every target loaded it, whatever is left at the end of a run.
+// Every file is written or read with its (de)compression on a thread of the blocking pool, fed
+// through a small channel, off the tasks copying slices.
+use crate::config::{CopyFormat, SpoolCompression, SpoolConfig};
+use bytes::Bytes;
+use chrono::Utc;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+// Chunk size when loading from a spool file
+const READ_SIZE: usize = 64 * 1024;
+// chunks queued for the thread writing a file or read ahead by the one reading it
+const QUEUED_CHUNKS: usize = 16;
+
+pub struct Spool {
+    dir: PathBuf,
+    compression: SpoolCompression,
+    keep: bool,
+    // part of every file name, files of other runs are never loaded
+    run: String,
+}
+
+impl Spool {
+    pub fn create(config: &SpoolConfig) -> Result<Spool, Box<dyn Error>> {
+        let dir = PathBuf::from(&config.directory);
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create spool directory {}: {}", dir.display(), e))?;
+        let run = format!("{}-{}", Utc::now().format("%Y%m%dT%H%M%S"), std::process::id());
+        Ok(Spool { dir, compression: config.compression, keep: config.keep, run })
+    }
+
+    pub fn path_for(&self, table_name: &str, query: &str, copy_format: CopyFormat) -> PathBuf {
+        let digest = Sha256::digest(format!("{}\n{}", copy_format.copy_options(), query).as_bytes());
+        let extension = match self.compression {
+            SpoolCompression::Zstd => "zst",
+            SpoolCompression::Gzip => "gz",
+        };
+        self.dir.join(format!("{}-{}-{}.copy.{}", self.run, file_name(table_name), &hex::encode(digest)[..16], extension))
+    }
+
+    // A complete file of this run, see writer
+    pub async fn exists(&self, path: &Path) -> Result<bool, Box<dyn Error>> {
+        Ok(tokio::fs::try_exists(path).await?)
+    }
+
+    // Written to `<path>.part` and renamed by `SpoolWriter::finish`, so a file that exists is complete
+    pub fn writer(&self, path: &Path) -> SpoolWriter {
+        let (tx, mut rx) = mpsc::channel(QUEUED_CHUNKS);
+        let (part, path) = (path.with_extension("part"), path.to_path_buf());
+        let compression = self.compression;
+        let task = tokio::task::spawn_blocking(move || {
+            let file = BufWriter::new(File::create(&part)?);
+            let mut encoder = match compression {
+                SpoolCompression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 3)?),
+                SpoolCompression::Gzip => Encoder::Gzip(GzEncoder::new(file, flate2::Compression::fast())),
+            };
+            let mut bytes = 0;
+            loop {
+                match rx.blocking_recv() {
+                    Some(WriterMessage::Chunk(chunk)) => {
+                        encoder.write_all(&chunk)?;
+                        bytes += chunk.len() as u64;
+                    }
+                    Some(WriterMessage::Finish) => break,
+                    // the extraction failed, the partial file is discarded
+                    None => return Err(io::Error::new(ErrorKind::Interrupted, "spool file abandoned")),
+                }
+            }
+            let file = encoder.finish()?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&part, &path)?;
+            Ok(bytes)
+        });
+        SpoolWriter { tx, task }
+    }
+
+    pub fn reader(&self, path: &Path) -> SpoolReader {
+        let (tx, rx) = mpsc::channel(QUEUED_CHUNKS);
+        let path = path.to_path_buf();
+        let compression = self.compression;
+        tokio::task::spawn_blocking(move || {
+            let opened = File::open(&path).and_then(|file| {
+                Ok(match compression {
+                    SpoolCompression::Zstd => Box::new(zstd::Decoder::new(file)?) as Box<dyn Read>,
+                    SpoolCompression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+                })
+            });
+            let mut reader = match opened {
+                Ok(reader) => reader,
+                Err(e) => return drop(tx.blocking_send(Err(e))),
+            };
+            loop {
+                let mut buffer = vec![0; READ_SIZE];
+                let chunk = reader.read(&mut buffer).map(|n| {
+                    buffer.truncate(n);
+                    Bytes::from(buffer)
+                });
+                let done = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+                // the receiver is gone when the load stopped early
+                if tx.blocking_send(chunk).is_err() || done {
+                    return;
+                }
+            }
+        });
+        SpoolReader { rx }
+    }
+
+    // Once every target loaded the file, unless the files are to be kept
+    pub async fn remove(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if self.keep {
+            info!(path = %path.display(), "spool file kept");
+            return Ok(());
+        }
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    // The partial file of an extraction that failed
+    pub async fn discard(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        match tokio::fs::remove_file(path.with_extension("part")).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Removes what's left at the end of the run: files of targets that failed, partial files and
+    // files of earlier runs
+    pub async fn cleanup(self) -> Result<(), Box<dyn Error>> {
+        if self.keep {
+            return Ok(());
+        }
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.file_name().is_some_and(|name| name.to_string_lossy().contains(".copy.")) {
+                info!(path = %path.display(), "removing leftover spool file");
+                tokio::fs::remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Characters that don't belong in a file name replaced
+fn file_name(table_name: &str) -> String {
+    table_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+enum Encoder {
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Encoder {
+    fn write_all(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(e) => e.write_all(chunk),
+            Encoder::Gzip(e) => e.write_all(chunk),
+        }
+    }
+
+    fn finish(self) -> io::Result<BufWriter<File>> {
+        match self {
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Gzip(e) => e.finish(),
+        }
+    }
+}
+
+enum WriterMessage {
+    Chunk(Bytes),
+    Finish,
+}
+
+pub struct SpoolWriter {
+    tx: mpsc::Sender<WriterMessage>,
+    // the thread writing the file, returns the uncompressed size
+    task: JoinHandle<io::Result<u64>>,
+}
+
+impl SpoolWriter {
+    pub async fn write(&mut self, chunk: Bytes) -> Result<(), Box<dyn Error>> {
+        if self.tx.send(WriterMessage::Chunk(chunk)).await.is_err() {
+            // the thread stopped on an error
+            (&mut self.task).await??;
+            return Err("Spool file writer stopped".into());
+        }
+        Ok(())
+    }
+
+    // Flushes the compressed stream to disk, returns the uncompressed size
+    pub async fn finish(self) -> Result<u64, Box<dyn Error>> {
+        // a failed send leaves the thread's error to the task
+        let _ = self.tx.send(WriterMessage::Finish).await;
+        Ok(self.task.await??)
+    }
+}
+
+pub struct SpoolReader {
+    // chunks read ahead, an empty one at the end of the file
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+}
+
+impl SpoolReader {
+    // The next chunk of uncompressed data, None at the end of the file
+    pub async fn read(&mut self) -> Result<Option<Bytes>, Box<dyn Error>> {
+        match self.rx.recv().await {
+            Some(Ok(chunk)) if chunk.is_empty() => Ok(None),
+            Some(Ok(chunk)) => Ok(Some(chunk)),
+            Some(Err(e)) => Err(e.into()),
+            // the thread ended without reaching the end of the file
+            None => Err("Spool file reader stopped".into()),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// Small helpers for building SQL text from identifiers coming from config or catalogs
+
+// Quote a single identifier: my"col -> "my""col"
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+// Quote a possibly schema-qualified name: public.table1 -> "public"."table1"
+pub fn quote_qualified(name: &str) -> String {
+    name.split('.')
+        .map(quote_ident)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+
+// Quote a string literal: it's -> 'it''s'
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Comma separated quoted identifiers: [a, b] -> "a", "b"
+pub fn column_list(columns: &[String]) -> String {
+    columns.iter().map(|c| quote_ident(c)).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,84 @@
+// Per-table sync state (watermarks) kept on the target in transform.sync_state. Cursor tables
+// take their watermark from the target rows, unless a copy that commits in parts left a pinned
+// cursor condition: that one is used until a copy covering it is complete.
+use sqlx::{PgExecutor, PgPool};
+use std::error::Error;
+
+pub async fn ensure_state_table(target_pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query("CREATE SCHEMA IF NOT EXISTS transform").execute(target_pool).await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS transform.sync_state (
+            table_name text PRIMARY KEY,
+            xmin_watermark xid8,
+            updated_at timestamptz NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(target_pool)
+    .await?;
+    sqlx::query("ALTER TABLE transform.sync_state ADD COLUMN IF NOT EXISTS cursor_condition text")
+        .execute(target_pool)
+        .await?;
+    Ok(())
+}
+
+// Snapshot xmin of the source at the start of the last successful xmin based sync
+pub async fn load_xmin_watermark(target_pool: &PgPool, table_name: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    let watermark: Option<Option<String>> =
+        sqlx::query_scalar("SELECT xmin_watermark::text FROM transform.sync_state WHERE table_name = $1")
+            .bind(table_name)
+            .fetch_optional(target_pool)
+            .await?;
+    match watermark.flatten() {
+        Some(xid) => Ok(Some(xid.parse()?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_xmin_watermark<'e>(
+    executor: impl PgExecutor<'e>,
+    table_name: &str,
+    watermark: u64,
+) -> Result<(), Box<dyn Error>> {
+    sqlx::query(
+        "INSERT INTO transform.sync_state (table_name, xmin_watermark, updated_at)
+         VALUES ($1, $2::text::xid8, now())
+         ON CONFLICT (table_name) DO UPDATE SET xmin_watermark = EXCLUDED.xmin_watermark, updated_at = EXCLUDED.updated_at",
+    )
+    .bind(table_name)
+    .bind(watermark.to_string())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// Cursor predicate the next copy has to start from instead of the target's highest cursor values
+pub async fn load_cursor_condition(target_pool: &PgPool, table_name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let condition: Option<Option<String>> =
+        sqlx::query_scalar("SELECT cursor_condition FROM transform.sync_state WHERE table_name = $1")
+            .bind(table_name)
+            .fetch_optional(target_pool)
+            .await?;
+    Ok(condition.flatten())
+}
+
+pub async fn pin_cursor_condition<'e>(executor: impl PgExecutor<'e>, table_name: &str, condition: &str) -> Result<(), Box<dyn Error>> {
+    sqlx::query(
+        "INSERT INTO transform.sync_state (table_name, cursor_condition, updated_at)
+         VALUES ($1, $2, now())
+         ON CONFLICT (table_name) DO UPDATE SET cursor_condition = EXCLUDED.cursor_condition, updated_at = EXCLUDED.updated_at",
+    )
+    .bind(table_name)
+    .bind(condition)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// Run once everything since the pinned condition has been committed
+pub async fn clear_cursor_condition<'e>(executor: impl PgExecutor<'e>, table_name: &str) -> Result<(), Box<dyn Error>> {
+    sqlx::query("UPDATE transform.sync_state SET cursor_condition = NULL, updated_at = now() WHERE table_name = $1 AND cursor_condition IS NOT NULL")
+        .bind(table_name)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+// Throttling of the COPY stream so a sync can run against a busy primary. Bytes and rows are
+// limited to an average rate since the start of the copy (time spent paused doesn't count), and
+// the copy pauses while the source's replicas lag or it has too many active connections.
+use crate::config::ThrottleConfig;
+use sqlx::PgPool;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const DEFAULT_CHECK_INTERVAL_MS: u64 = 5_000;
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    started: Instant,
+    paused: Duration,
+    last_check: Option<Instant>,
+    bytes: u64,
+    rows: u64,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle { config, started: Instant::now(), paused: Duration::ZERO, last_check: None, bytes: 0, rows: 0 }
+    }
+
+    fn check_interval(&self) -> Duration {
+        Duration::from_millis(self.config.check_interval_ms.unwrap_or(DEFAULT_CHECK_INTERVAL_MS))
+    }
+
+    fn checks_source(&self) -> bool {
+        self.config.max_replication_lag_secs.is_some() || self.config.max_active_connections.is_some()
+    }
+
+    // Highest replay lag of the source's standbys in seconds
+    async fn replication_lag(source_pool: &PgPool) -> Result<f64, Box<dyn Error>> {
+        let lag: f64 = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(EXTRACT(EPOCH FROM replay_lag)), 0)::float8 FROM pg_stat_replication",
+        )
+        .fetch_one(source_pool)
+        .await?;
+        Ok(lag)
+    }
+
+    async fn active_connections(source_pool: &PgPool) -> Result<i64, Box<dyn Error>> {
+        let active: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_stat_activity
+             WHERE state = 'active' AND backend_type = 'client backend' AND pid <> pg_backend_pid()",
+        )
+        .fetch_one(source_pool)
+        .await?;
+        Ok(active)
+    }
+
+    // Reason to hold off, None if the source is within the thresholds
+    async fn overloaded(&self, source_pool: &PgPool) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(max_lag) = self.config.max_replication_lag_secs {
+            let lag = Self::replication_lag(source_pool).await?;
+            if lag > max_lag {
+                return Ok(Some(format!("replication lag {:.1}s over {}s", lag, max_lag)));
+            }
+        }
+        if let Some(max_active) = self.config.max_active_connections {
+            let active = Self::active_connections(source_pool).await?;
+            if active > max_active {
+                return Ok(Some(format!("{} active connections over {}", active, max_active)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Waits until the source is within the lag/connection thresholds, checked at most once per interval
+    pub async fn wait_for_source(&mut self, source_pool: &PgPool, table_name: &str) -> Result<(), Box<dyn Error>> {
+        if !self.checks_source() || self.last_check.is_some_and(|t| t.elapsed() < self.check_interval()) {
+            return Ok(());
+        }
+        loop {
+            self.last_check = Some(Instant::now());
+            let Some(reason) = self.overloaded(source_pool).await? else {
+                return Ok(());
+            };
+            info!(table = %table_name, reason = %reason, "pausing");
+            let interval = self.check_interval();
+            tokio::time::sleep(interval).await;
+            self.paused += interval;
+        }
+    }
+
+    // Accounts for a chunk of the stream and sleeps as long as needed to stay under the rate limits.
+    // Rows are counted as lines, so values with embedded newlines count more than once.
+    pub async fn consume(&mut self, chunk: &[u8]) {
+        self.bytes += chunk.len() as u64;
+        if self.config.rows_per_sec.is_some() {
+            self.rows += chunk.iter().filter(|&&b| b == b'\n').count() as u64;
+        }
+        let mut expected = Duration::ZERO;
+        if let Some(bytes_per_sec) = self.config.bytes_per_sec.filter(|&r| r > 0) {
+            expected = expected.max(Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64));
+        }
+        if let Some(rows_per_sec) = self.config.rows_per_sec.filter(|&r| r > 0) {
+            expected = expected.max(Duration::from_secs_f64(self.rows as f64 / rows_per_sec as f64));
+        }
+        let elapsed = self.started.elapsed().saturating_sub(self.paused);
+        if expected > elapsed {
+            tokio::time::sleep(expected - elapsed).await;
+        }
+    }
+}
@@ -0,0 +1,349 @@
+// Row transformation stage between COPY OUT and COPY IN. The CSV stream coming from the source
+// is parsed into rows (a chunk can end anywhere, in the middle of a row or a quoted value),
+// per-column transforms are applied and the rows are re-encoded for the target.
+// Values follow the COPY CSV conventions: an unquoted empty field is NULL, "" is an empty string.
+use crate::config::{TableConfig, TransformConfig};
+use crate::masking::Masker;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+// One parsed row, None is NULL
+pub type CopyRow = Vec<Option<String>>;
+
+// A transform of a single column. `row` is the untransformed source row and `header` its column
+// names, so values can also be derived from other columns.
+pub trait ColumnTransform: Send + Sync {
+    fn apply(&self, value: Option<String>, row: &CopyRow, header: &[String]) -> Result<Option<String>, Box<dyn Error>>;
+}
+
+impl ColumnTransform for TransformConfig {
+    fn apply(&self, value: Option<String>, row: &CopyRow, header: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+        let transformed = match self {
+            TransformConfig::Trim => value.map(|v| v.trim().to_string()),
+            TransformConfig::Lowercase => value.map(|v| v.to_lowercase()),
+            TransformConfig::Uppercase => value.map(|v| v.to_uppercase()),
+            TransformConfig::EmptyToNull => value.filter(|v| !v.is_empty()),
+            TransformConfig::NullIf(null_value) => value.filter(|v| v != null_value),
+            TransformConfig::Default(default) => value.or_else(|| Some(default.clone())),
+            TransformConfig::CopyFrom(column) => {
+                let index = header
+                    .iter()
+                    .position(|c| c == column)
+                    .ok_or_else(|| format!("copy_from column {} is not in the COPY output", column))?;
+                row[index].clone()
+            }
+        };
+        Ok(transformed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParseState {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    // a quote inside a quoted field: either the end of the field or the first half of ""
+    QuoteInQuoted,
+}
+
+// Incremental parser for COPY ... WITH CSV output
+struct CsvParser {
+    state: ParseState,
+    field: Vec<u8>,
+    quoted: bool,
+    row: CopyRow,
+}
+
+impl CsvParser {
+    fn new() -> Self {
+        CsvParser { state: ParseState::FieldStart, field: Vec::new(), quoted: false, row: Vec::new() }
+    }
+
+    fn end_field(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes = std::mem::take(&mut self.field);
+        let value = if bytes.is_empty() && !self.quoted {
+            None
+        } else {
+            Some(String::from_utf8(bytes)?)
+        };
+        self.row.push(value);
+        self.quoted = false;
+        self.state = ParseState::FieldStart;
+        Ok(())
+    }
+
+    fn end_row(&mut self, rows: &mut Vec<CopyRow>) -> Result<(), Box<dyn Error>> {
+        self.end_field()?;
+        rows.push(std::mem::take(&mut self.row));
+        Ok(())
+    }
+
+    // Parse a chunk, returning the rows completed by it
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<CopyRow>, Box<dyn Error>> {
+        let mut rows = Vec::new();
+        for &byte in chunk {
+            match (self.state, byte) {
+                (ParseState::FieldStart, b'"') => {
+                    self.quoted = true;
+                    self.state = ParseState::Quoted;
+                }
+                (ParseState::FieldStart | ParseState::Unquoted | ParseState::QuoteInQuoted, b',') => self.end_field()?,
+                (ParseState::FieldStart | ParseState::Unquoted | ParseState::QuoteInQuoted, b'\n') => self.end_row(&mut rows)?,
+                // \r of a \r\n line ending
+                (ParseState::FieldStart | ParseState::Unquoted | ParseState::QuoteInQuoted, b'\r') => {}
+                (ParseState::FieldStart | ParseState::Unquoted, other) => {
+                    self.field.push(other);
+                    self.state = ParseState::Unquoted;
+                }
+                (ParseState::Quoted, b'"') => self.state = ParseState::QuoteInQuoted,
+                (ParseState::Quoted, other) => self.field.push(other),
+                (ParseState::QuoteInQuoted, b'"') => {
+                    self.field.push(b'"');
+                    self.state = ParseState::Quoted;
+                }
+                (ParseState::QuoteInQuoted, other) => {
+                    return Err(format!("Unexpected {:?} after closing quote in COPY CSV data", other as char).into());
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    // A last row without a trailing newline
+    fn finish(&mut self) -> Result<Option<CopyRow>, Box<dyn Error>> {
+        match self.state {
+            ParseState::Quoted => Err("COPY CSV data ended inside a quoted value".into()),
+            ParseState::FieldStart if self.row.is_empty() => Ok(None),
+            _ => {
+                self.end_field()?;
+                Ok(Some(std::mem::take(&mut self.row)))
+            }
+        }
+    }
+}
+
+fn encode_row(row: &CopyRow, out: &mut Vec<u8>) {
+    for (i, value) in row.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        if let Some(value) = value {
+            // quote whatever would otherwise be read back differently (empty string vs NULL, \. end marker)
+            let needs_quotes = value.is_empty()
+                || value == "\\."
+                || value.contains([',', '"', '\n', '\r']);
+            if needs_quotes {
+                out.push(b'"');
+                out.extend_from_slice(value.replace('"', "\"\"").as_bytes());
+                out.push(b'"');
+            } else {
+                out.extend_from_slice(value.as_bytes());
+            }
+        }
+    }
+    out.push(b'\n');
+}
+
+// Parses a `COPY ... WITH CSV HEADER` stream and applies the column transforms to every row
+pub struct TransformPipeline {
+    // (column name, transforms in order)
+    columns: Vec<(String, Vec<Box<dyn ColumnTransform>>)>,
+    // column positions resolved from the header
+    resolved: Vec<(usize, usize)>,
+    header: Option<Vec<String>>,
+    parser: CsvParser,
+    rows: u64,
+}
+
+impl Default for TransformPipeline {
+    fn default() -> Self {
+        TransformPipeline { columns: Vec::new(), resolved: Vec::new(), header: None, parser: CsvParser::new(), rows: 0 }
+    }
+}
+
+impl TransformPipeline {
+    pub fn from_config(transforms: &BTreeMap<String, Vec<TransformConfig>>) -> Self {
+        let mut pipeline = TransformPipeline::default();
+        for (column, column_transforms) in transforms {
+            for transform in column_transforms {
+                pipeline.add(column, Box::new(transform.clone()));
+            }
+        }
+        pipeline
+    }
+
+    // The table's transforms followed by its masking rules
+    pub fn for_table(table: &TableConfig) -> Result<Self, Box<dyn Error>> {
+        let mut pipeline = TransformPipeline::from_config(&table.transforms);
+        let masker = Masker::from_env();
+        for (column, rule) in &table.masking {
+            masker.check(column, rule)?;
+            pipeline.add(column, Box::new(masker.column_mask(rule.clone())));
+        }
+        Ok(pipeline)
+    }
+
+    pub fn add(&mut self, column: &str, transform: Box<dyn ColumnTransform>) {
+        match self.columns.iter_mut().find(|(name, _)| name == column) {
+            Some((_, transforms)) => transforms.push(transform),
+            None => self.columns.push((column.to_string(), vec![transform])),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    fn set_header(&mut self, header: CopyRow) -> Result<(), Box<dyn Error>> {
+        let header: Vec<String> = header.into_iter().map(|c| c.unwrap_or_default()).collect();
+        for (i, (column, _)) in self.columns.iter().enumerate() {
+            let position = header
+                .iter()
+                .position(|c| c == column)
+                .ok_or_else(|| format!("Transformed column {} is not in the COPY output", column))?;
+            self.resolved.push((i, position));
+        }
+        self.header = Some(header);
+        Ok(())
+    }
+
+    fn transform_row(&self, row: CopyRow) -> Result<CopyRow, Box<dyn Error>> {
+        let header = self.header.as_deref().unwrap_or_default();
+        if row.len() != header.len() {
+            return Err(format!("COPY row has {} values, header has {} columns", row.len(), header.len()).into());
+        }
+        let mut transformed = row.clone();
+        for &(column, position) in &self.resolved {
+            let mut value = transformed[position].take();
+            for transform in &self.columns[column].1 {
+                value = transform.apply(value, &row, header)?;
+            }
+            transformed[position] = value;
+        }
+        Ok(transformed)
+    }
+
+    fn encode(&mut self, rows: Vec<CopyRow>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut out = Vec::new();
+        for row in rows {
+            if self.header.is_none() {
+                encode_row(&row, &mut out);
+                self.set_header(row)?;
+                continue;
+            }
+            encode_row(&self.transform_row(row)?, &mut out);
+            self.rows += 1;
+        }
+        Ok(out)
+    }
+
+    // Feed a chunk from COPY OUT, returns the re-encoded complete rows (possibly nothing yet)
+    pub fn process(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let rows = self.parser.feed(chunk)?;
+        self.encode(rows)
+    }
+
+    // End of the COPY OUT stream
+    pub fn finish(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let rows = self.parser.finish()?.into_iter().collect();
+        self.encode(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(v: &str) -> Option<String> {
+        Some(v.to_string())
+    }
+
+    fn parse(chunks: &[&[u8]]) -> Vec<CopyRow> {
+        let mut parser = CsvParser::new();
+        let mut rows = Vec::new();
+        for chunk in chunks {
+            rows.extend(parser.feed(chunk).unwrap());
+        }
+        rows.extend(parser.finish().unwrap());
+        rows
+    }
+
+    #[test]
+    fn empty_string_is_not_null() {
+        assert_eq!(parse(&[b"a,,\"\",\"x\"\"y\"\n"]), vec![vec![value("a"), None, value(""), value("x\"y")]]);
+    }
+
+    #[test]
+    fn quoted_newlines_and_commas() {
+        let rows = parse(&[b"1,\"line\nbreak, and \r\n\"\r\n2,b\r\n"]);
+        assert_eq!(rows, vec![vec![value("1"), value("line\nbreak, and \r\n")], vec![value("2"), value("b")]]);
+    }
+
+    #[test]
+    fn rows_split_across_chunks() {
+        let data: &[u8] = b"id,note\n1,\"a \"\"quoted\"\", value\"\n2,\n";
+        let whole = parse(&[data]);
+        assert_eq!(whole.len(), 3);
+        // every split point, including between the two quotes of an escaped quote
+        for split in 0..=data.len() {
+            assert_eq!(parse(&[&data[..split], &data[split..]]), whole, "split at {}", split);
+        }
+        let bytes: Vec<&[u8]> = data.chunks(1).collect();
+        assert_eq!(parse(&bytes), whole);
+    }
+
+    #[test]
+    fn multibyte_values_split_across_chunks() {
+        let data = "ä,\"ö\n\"\n".as_bytes();
+        assert_eq!(parse(&[&data[..1], &data[1..5], &data[5..]]), vec![vec![value("ä"), value("ö\n")]]);
+    }
+
+    #[test]
+    fn last_row_without_newline() {
+        assert_eq!(parse(&[b"1,a\n2,"]), vec![vec![value("1"), value("a")], vec![value("2"), None]]);
+        assert_eq!(parse(&[b""]), Vec::<CopyRow>::new());
+    }
+
+    #[test]
+    fn malformed_data() {
+        let mut parser = CsvParser::new();
+        assert!(parser.feed(b"\"a\"b\n").is_err());
+        let mut parser = CsvParser::new();
+        parser.feed(b"1,\"open").unwrap();
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn encoded_rows_parse_back() {
+        let rows = vec![vec![value(""), None, value("\\."), value("a,\"b\"\nc"), value("plain")]];
+        let mut out = Vec::new();
+        encode_row(&rows[0], &mut out);
+        assert_eq!(out, b"\"\",,\"\\.\",\"a,\"\"b\"\"\nc\",plain\n");
+        assert_eq!(parse(&[&out]), rows);
+    }
+
+    #[test]
+    fn pipeline_transforms_by_header() {
+        let mut transforms = BTreeMap::new();
+        transforms.insert("email".to_string(), vec![TransformConfig::Trim, TransformConfig::Lowercase]);
+        transforms.insert("note".to_string(), vec![TransformConfig::NullIf("N/A".to_string())]);
+        let mut pipeline = TransformPipeline::from_config(&transforms);
+        let mut out = pipeline.process(b"id,email,note\n1, A@B.C ,N/").unwrap();
+        out.extend(pipeline.process(b"A\n2,,\"\"\n").unwrap());
+        out.extend(pipeline.finish().unwrap());
+        assert_eq!(out, b"id,email,note\n1,a@b.c,\n2,,\"\"\n");
+        assert_eq!(pipeline.rows(), 2);
+    }
+
+    #[test]
+    fn pipeline_missing_column() {
+        let mut transforms = BTreeMap::new();
+        transforms.insert("missing".to_string(), vec![TransformConfig::Trim]);
+        let mut pipeline = TransformPipeline::from_config(&transforms);
+        assert!(pipeline.process(b"id\n1\n").is_err());
+    }
+}
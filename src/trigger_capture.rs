@@ -0,0 +1,227 @@
+// Trigger based change capture for sources where we can't create replication slots.
+// `install` adds a changelog table and a generic AFTER trigger to every configured table,
+// the drain reads the changelog in id order, applies the operations to the target and deletes
+// the drained entries from the source once the target transaction has committed.
+use crate::catalog::primary_key_columns;
+use crate::cdc::{apply_change, Change, ColumnTypesCache, ColumnValues, TableRef};
+use crate::config::{SyncConfig, TriggerConfig};
+use crate::sql::quote_ident;
+use serde_json::Value;
+use sqlx::types::Json;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::error::Error;
+use tracing::info;
+
+const TRIGGER_NAME: &str = "sync_capture_change";
+const TRUNCATE_TRIGGER_NAME: &str = "sync_capture_truncate";
+
+fn changelog_table(config: &TriggerConfig) -> String {
+    format!("{}.changelog", quote_ident(&config.schema))
+}
+
+// Rows are stored as jsonb objects of the columns' text representation (not to_jsonb),
+// so arrays, ranges etc. can be cast back with their input functions on the target
+fn install_statements(config: &TriggerConfig) -> Vec<String> {
+    let schema = quote_ident(&config.schema);
+    let changelog = changelog_table(config);
+    vec![
+        format!("CREATE SCHEMA IF NOT EXISTS {}", schema),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id bigserial PRIMARY KEY,
+                schema_name text NOT NULL,
+                table_name text NOT NULL,
+                op char(1) NOT NULL,
+                old_row jsonb,
+                new_row jsonb,
+                txid bigint NOT NULL DEFAULT txid_current(),
+                changed_at timestamptz NOT NULL DEFAULT clock_timestamp()
+            )",
+            changelog
+        ),
+        format!(
+            "CREATE OR REPLACE FUNCTION {schema}.row_as_text(r anyelement, relid oid) RETURNS jsonb
+            LANGUAGE plpgsql AS $fn$
+            DECLARE
+                col text;
+                val text;
+                result jsonb := '{{}}';
+            BEGIN
+                FOR col IN SELECT attname FROM pg_attribute WHERE attrelid = relid AND attnum > 0 AND NOT attisdropped ORDER BY attnum LOOP
+                    EXECUTE format('SELECT ($1).%I::text', col) USING r INTO val;
+                    result := result || jsonb_build_object(col, val);
+                END LOOP;
+                RETURN result;
+            END
+            $fn$",
+            schema = schema
+        ),
+        format!(
+            "CREATE OR REPLACE FUNCTION {schema}.capture_change() RETURNS trigger
+            LANGUAGE plpgsql AS $fn$
+            BEGIN
+                IF TG_OP = 'INSERT' THEN
+                    INSERT INTO {changelog} (schema_name, table_name, op, new_row)
+                    VALUES (TG_TABLE_SCHEMA, TG_TABLE_NAME, 'I', {schema}.row_as_text(NEW, TG_RELID));
+                ELSIF TG_OP = 'UPDATE' THEN
+                    INSERT INTO {changelog} (schema_name, table_name, op, old_row, new_row)
+                    VALUES (TG_TABLE_SCHEMA, TG_TABLE_NAME, 'U', {schema}.row_as_text(OLD, TG_RELID), {schema}.row_as_text(NEW, TG_RELID));
+                ELSIF TG_OP = 'DELETE' THEN
+                    INSERT INTO {changelog} (schema_name, table_name, op, old_row)
+                    VALUES (TG_TABLE_SCHEMA, TG_TABLE_NAME, 'D', {schema}.row_as_text(OLD, TG_RELID));
+                ELSE
+                    INSERT INTO {changelog} (schema_name, table_name, op)
+                    VALUES (TG_TABLE_SCHEMA, TG_TABLE_NAME, 'T');
+                END IF;
+                RETURN NULL;
+            END
+            $fn$",
+            schema = schema,
+            changelog = changelog
+        ),
+    ]
+}
+
+pub async fn install(source_pool: &PgPool, config: &SyncConfig) -> Result<(), Box<dyn Error>> {
+    let trigger = &config.trigger;
+    for statement in install_statements(trigger) {
+        sqlx::query(&statement).execute(source_pool).await?;
+    }
+    let schema = quote_ident(&trigger.schema);
+    for table in config.table_names() {
+        let table = TableRef::parse(table).quoted();
+        let statements = [
+            format!("DROP TRIGGER IF EXISTS {} ON {}", TRIGGER_NAME, table),
+            format!(
+                "CREATE TRIGGER {} AFTER INSERT OR UPDATE OR DELETE ON {} FOR EACH ROW EXECUTE FUNCTION {}.capture_change()",
+                TRIGGER_NAME, table, schema
+            ),
+            format!("DROP TRIGGER IF EXISTS {} ON {}", TRUNCATE_TRIGGER_NAME, table),
+            format!(
+                "CREATE TRIGGER {} AFTER TRUNCATE ON {} FOR EACH STATEMENT EXECUTE FUNCTION {}.capture_change()",
+                TRUNCATE_TRIGGER_NAME, table, schema
+            ),
+        ];
+        for statement in statements {
+            sqlx::query(&statement).execute(source_pool).await?;
+        }
+        info!(table = %table, "installed change capture triggers");
+    }
+    Ok(())
+}
+
+pub async fn uninstall(source_pool: &PgPool, config: &SyncConfig) -> Result<(), Box<dyn Error>> {
+    let trigger = &config.trigger;
+    for table in config.table_names() {
+        let table = TableRef::parse(table).quoted();
+        for name in [TRIGGER_NAME, TRUNCATE_TRIGGER_NAME] {
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS {} ON {}", name, table))
+                .execute(source_pool)
+                .await?;
+        }
+        info!(table = %table, "removed change capture triggers");
+    }
+    let schema = quote_ident(&trigger.schema);
+    let statements = [
+        format!("DROP FUNCTION IF EXISTS {}.capture_change()", schema),
+        format!("DROP FUNCTION IF EXISTS {}.row_as_text(anyelement, oid)", schema),
+        format!("DROP TABLE IF EXISTS {}", changelog_table(trigger)),
+    ];
+    for statement in statements {
+        sqlx::query(&statement).execute(source_pool).await?;
+    }
+    info!(changelog = %changelog_table(trigger), "removed changelog");
+    Ok(())
+}
+
+fn row_values(row: &Option<Json<Value>>) -> ColumnValues {
+    match row {
+        Some(Json(Value::Object(map))) => map
+            .iter()
+            .map(|(name, value)| (name.clone(), value.as_str().map(String::from)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Primary key values of the row, or the whole row for tables without a primary key
+fn key_values(primary_key: &[String], values: &ColumnValues) -> ColumnValues {
+    if primary_key.is_empty() {
+        return values.clone();
+    }
+    values
+        .iter()
+        .filter(|(name, _)| primary_key.contains(name))
+        .cloned()
+        .collect()
+}
+
+// Drain the changelog until it is empty
+pub async fn drain(source_pool: &PgPool, target_pool: &PgPool, config: &SyncConfig) -> Result<(), Box<dyn Error>> {
+    let trigger = &config.trigger;
+    let changelog = changelog_table(trigger);
+    let mut primary_keys: HashMap<TableRef, Vec<String>> = HashMap::new();
+    let mut types_cache = ColumnTypesCache::new();
+    let mut total = 0;
+
+    loop {
+        let rows = sqlx::query(&format!(
+            "SELECT id, schema_name, table_name, op::text, old_row, new_row FROM {} ORDER BY id LIMIT $1",
+            changelog
+        ))
+        .bind(trigger.batch_size)
+        .fetch_all(source_pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let mut ids: Vec<i64> = Vec::with_capacity(rows.len());
+        let mut changes = Vec::with_capacity(rows.len());
+        for row in &rows {
+            ids.push(row.try_get("id")?);
+            let table = TableRef {
+                schema: row.try_get("schema_name")?,
+                name: row.try_get("table_name")?,
+            };
+            if !primary_keys.contains_key(&table) {
+                let key = primary_key_columns(source_pool, &table.quoted()).await?;
+                primary_keys.insert(table.clone(), key);
+            }
+            let primary_key = &primary_keys[&table];
+            let old = row_values(&row.try_get("old_row")?);
+            let new = row_values(&row.try_get("new_row")?);
+            let op: String = row.try_get("op")?;
+            match op.as_str() {
+                // Delete by key first so re-applying a batch after a failed cleanup doesn't duplicate rows
+                "I" => {
+                    if !primary_key.is_empty() {
+                        changes.push(Change::Delete { table: table.clone(), key: key_values(primary_key, &new) });
+                    }
+                    changes.push(Change::Insert { table, new });
+                }
+                "U" => changes.push(Change::Update { key: key_values(primary_key, &old), table, new }),
+                "D" => changes.push(Change::Delete { key: key_values(primary_key, &old), table }),
+                "T" => changes.push(Change::Truncate { tables: vec![table] }),
+                other => return Err(format!("Unknown changelog operation {:?}", other).into()),
+            }
+        }
+
+        let mut tx = target_pool.begin().await?;
+        for change in &changes {
+            apply_change(&mut tx, target_pool, &mut types_cache, change).await?;
+        }
+        tx.commit().await?;
+
+        sqlx::query(&format!("DELETE FROM {} WHERE id = ANY($1)", changelog))
+            .bind(&ids)
+            .execute(source_pool)
+            .await?;
+        total += ids.len();
+        info!(entries = ids.len(), total, "applied changelog entries");
+    }
+
+    info!(changelog = %changelog, total, "changelog drained");
+    Ok(())
+}
@@ -0,0 +1,225 @@
+// `verify`: compares the row counts of every configured table between the source(s) and the
+// target(s), optionally only within a window of the table's cursor column
+// (`verify --since 2024-01-01 --until 2024-02-01`), so rows written after the last sync don't
+// show up as differences. The table's filter applies on the source, the rows of the source being
+// checked on fan-in targets. Prints a table (or `--json`) and fails if any count differs.
+// `verify --checksum` compares row contents instead, see checksum.rs.
+use crate::config::TableConfig;
+use crate::cursor;
+use crate::filter::where_clause;
+use crate::shard;
+use crate::sql::{quote_ident, quote_literal};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::error::Error;
+use tracing::warn;
+
+#[derive(Debug)]
+pub struct VerifyOptions {
+    pub json: bool,
+    // inclusive lower and exclusive upper bound on the cursor column, as SQL literals of its type
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // compare row contents by primary key range instead of counts, see checksum.rs
+    pub checksum: bool,
+    // rows per initial checksum chunk and the size at which a mismatching range stops being bisected
+    pub chunk_rows: i64,
+    pub leaf_rows: i64,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions { json: false, since: None, until: None, checksum: false, chunk_rows: 100_000, leaf_rows: 100 }
+    }
+}
+
+impl VerifyOptions {
+    // From the arguments after `verify`
+    pub fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut options = VerifyOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--checksum" => options.checksum = true,
+                "--since" => options.since = Some(args.next().ok_or("--since needs a value")?.clone()),
+                "--until" => options.until = Some(args.next().ok_or("--until needs a value")?.clone()),
+                "--chunk-rows" => options.chunk_rows = positive(arg, args.next())?,
+                "--leaf-rows" => options.leaf_rows = positive(arg, args.next())?,
+                other => return Err(format!("Unknown verify option {}", other).into()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn has_window(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+}
+
+fn positive(option: &str, value: Option<&String>) -> Result<i64, Box<dyn Error>> {
+    match value.and_then(|v| v.parse::<i64>().ok()) {
+        Some(value) if value > 0 => Ok(value),
+        _ => Err(format!("{} needs a positive number", option).into()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableCount {
+    pub table: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub target: String,
+    // cursor column the window was applied to, None without a window or a cursor column
+    pub window_column: Option<String>,
+    pub source_rows: i64,
+    pub target_rows: i64,
+}
+
+impl TableCount {
+    pub fn matches(&self) -> bool {
+        self.source_rows == self.target_rows
+    }
+}
+
+// The configured cursor column or the first of created_at/updated_at that can be used as one
+async fn window_column(pool: &PgPool, table: &TableConfig) -> Result<Option<String>, Box<dyn Error>> {
+    let candidates = match &table.cursor_column {
+        Some(column) => vec![column.as_str()],
+        None => vec!["created_at", "updated_at"],
+    };
+    for column in candidates {
+        if cursor::column_type(pool, &table.name, column).await?.is_some() {
+            return Ok(Some(column.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn window_conditions(column: &str, options: &VerifyOptions) -> Vec<String> {
+    let mut conditions = Vec::new();
+    if let Some(since) = &options.since {
+        conditions.push(format!("{} >= {}", quote_ident(column), quote_literal(since)));
+    }
+    if let Some(until) = &options.until {
+        conditions.push(format!("{} < {}", quote_ident(column), quote_literal(until)));
+    }
+    conditions
+}
+
+// Cursor column and conditions of the --since/--until window of a table, nothing without a window
+pub(crate) async fn window(pool: &PgPool, table: &TableConfig, options: &VerifyOptions) -> Result<(Option<String>, Vec<String>), Box<dyn Error>> {
+    if !options.has_window() {
+        return Ok((None, Vec::new()));
+    }
+    let column = window_column(pool, table).await?;
+    if column.is_none() {
+        warn!(table = %table.name, "no cursor column, using all of its rows");
+    }
+    let conditions = column.as_deref().map(|c| window_conditions(c, options)).unwrap_or_default();
+    Ok((column, conditions))
+}
+
+async fn count_rows(pool: &PgPool, table_name: &str, conditions: &[String]) -> Result<i64, Box<dyn Error>> {
+    let query = format!("SELECT count(*) FROM {} {}", table_name, where_clause(conditions));
+    Ok(sqlx::query_scalar(&query).fetch_one(pool).await?)
+}
+
+// Counts of every table for one source against every target
+pub async fn count_tables(
+    source_pool: &PgPool,
+    source_id: Option<&str>,
+    targets: &[(&str, &PgPool)],
+    tables: &[TableConfig],
+    options: &VerifyOptions,
+) -> Result<Vec<TableCount>, Box<dyn Error>> {
+    let mut counts = Vec::new();
+    for table in tables {
+        let (column, window) = window(source_pool, table, options).await?;
+        let mut source_conditions = window.clone();
+        source_conditions.extend(table.filter.clone());
+        let source_rows = count_rows(source_pool, &table.name, &source_conditions).await?;
+        let mut target_conditions = window;
+        target_conditions.extend(source_id.map(shard::source_condition));
+        for (target_name, target_pool) in targets {
+            counts.push(TableCount {
+                table: table.name.clone(),
+                source: source_id.map(str::to_string),
+                target: target_name.to_string(),
+                window_column: column.clone(),
+                source_rows,
+                target_rows: count_rows(target_pool, &table.name, &target_conditions).await?,
+            });
+        }
+    }
+    Ok(counts)
+}
+
+pub fn print_report(counts: &[TableCount], json: bool) -> Result<(), Box<dyn Error>> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(counts)?);
+        return Ok(());
+    }
+    let name = |count: &TableCount| shard::state_key(&count.table, count.source.as_deref());
+    let width = counts.iter().map(|c| name(c).len()).chain([5]).max().unwrap_or(5);
+    let target_width = counts.iter().map(|c| c.target.len()).chain([6]).max().unwrap_or(6);
+    println!("{:<width$}  {:<target_width$}  {:>12}  {:>12}  {:>10}  status", "table", "target", "source_rows", "target_rows", "diff");
+    for count in counts {
+        println!(
+            "{:<width$}  {:<target_width$}  {:>12}  {:>12}  {:>10}  {}",
+            name(count),
+            count.target,
+            count.source_rows,
+            count.target_rows,
+            count.target_rows - count.source_rows,
+            if count.matches() { "ok" } else { "MISMATCH" }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<VerifyOptions, Box<dyn Error>> {
+        VerifyOptions::parse(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert!(!options.json && !options.checksum && !options.has_window());
+        assert_eq!((options.chunk_rows, options.leaf_rows), (100_000, 100));
+    }
+
+    #[test]
+    fn all_options() {
+        let options = parse(&["--json", "--since", "2024-01-01", "--until", "2024-02-01", "--checksum", "--chunk-rows", "500", "--leaf-rows", "5"]).unwrap();
+        assert!(options.json && options.checksum && options.has_window());
+        assert_eq!(options.since.as_deref(), Some("2024-01-01"));
+        assert_eq!(options.until.as_deref(), Some("2024-02-01"));
+        assert_eq!((options.chunk_rows, options.leaf_rows), (500, 5));
+    }
+
+    #[test]
+    fn window_with_one_bound() {
+        let options = parse(&["--until", "100"]).unwrap();
+        assert!(options.has_window());
+        assert_eq!(window_conditions("id", &options), vec!["\"id\" < '100'"]);
+        let options = parse(&["--since", "it's"]).unwrap();
+        assert_eq!(window_conditions("created_at", &options), vec!["\"created_at\" >= 'it''s'"]);
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(parse(&["--since"]).is_err());
+        assert!(parse(&["--until"]).is_err());
+        assert!(parse(&["--chunk-rows"]).is_err());
+        assert!(parse(&["--chunk-rows", "0"]).is_err());
+        assert!(parse(&["--leaf-rows", "-1"]).is_err());
+        assert!(parse(&["--leaf-rows", "ten"]).is_err());
+        assert!(parse(&["--jsn"]).is_err());
+        assert!(parse(&["2024-01-01"]).is_err());
+    }
+}
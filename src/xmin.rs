@@ -0,0 +1,132 @@
+// Incremental sync by transaction id for tables without created_at/updated_at.
+// At the start of a run we take the source snapshot: every transaction below its xmin has
+// finished, so rows whose xmin is >= the previous run's snapshot xmin are the only ones that
+// can have changed since. Watermarks are stored as 64-bit xid8 values, row xmins are 32 bit
+// and get widened relative to the current snapshot (see `row_xid8_expr`).
+use sqlx::{PgExecutor, Row};
+use std::error::Error;
+use tracing::warn;
+
+const XID_SPACE: u64 = 1 << 32;
+// Unfrozen xids are always within 2^31 of the current one, beyond that the widening is ambiguous
+const MAX_SAFE_DISTANCE: u64 = 1 << 31;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SourceSnapshot {
+    pub xmin: u64,
+    pub xmax: u64,
+}
+
+pub async fn current_snapshot<'e>(executor: impl PgExecutor<'e>) -> Result<SourceSnapshot, Box<dyn Error>> {
+    let row = sqlx::query(
+        "SELECT pg_snapshot_xmin(s)::text AS xmin, pg_snapshot_xmax(s)::text AS xmax FROM pg_current_snapshot() AS s",
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(SourceSnapshot {
+        xmin: row.try_get::<String, _>("xmin")?.parse()?,
+        xmax: row.try_get::<String, _>("xmax")?.parse()?,
+    })
+}
+
+// xids below this are bootstrap/frozen and older than any watermark
+const FIRST_NORMAL_XID: u32 = 3;
+
+// How a row's 32-bit xmin is widened relative to a snapshot's xmax: xmins up to the low 32 bits
+// of xmax are in xmax's epoch, larger ones in the epoch before. A base can be negative in epoch
+// 0, those rows (from transactions after the snapshot) then look older than any watermark.
+#[derive(Debug, PartialEq)]
+struct XidEpochs {
+    // low 32 bits of xmax
+    low: u32,
+    // added to xmins up to `low`
+    current: i64,
+    // added to xmins above `low`
+    previous: i64,
+}
+
+fn xid_epochs(xmax: u64) -> XidEpochs {
+    let current = (xmax & !(XID_SPACE - 1)) as i64;
+    XidEpochs { low: xmax as u32, current, previous: current - XID_SPACE as i64 }
+}
+
+// 64-bit xid of a row's xmin (see xid_epochs), bootstrap/frozen xids map to 0. If the real
+// distance to xmax is larger than 2^32 the row only looks newer than it is, so it gets re-copied
+// rather than skipped.
+fn row_xid8_expr(xmax: u64) -> String {
+    let epochs = xid_epochs(xmax);
+    format!(
+        "CASE WHEN xmin::text::bigint < {first} THEN 0 \
+         WHEN xmin::text::bigint <= {low} THEN {current} + xmin::text::bigint \
+         ELSE {previous} + xmin::text::bigint END",
+        first = FIRST_NORMAL_XID,
+        low = epochs.low,
+        current = epochs.current,
+        previous = epochs.previous
+    )
+}
+
+// WHERE condition selecting rows changed since `watermark`, None when the table has to be copied in full
+pub fn changed_since(watermark: Option<u64>, snapshot: &SourceSnapshot) -> Option<String> {
+    let watermark = watermark?;
+    if snapshot.xmax.saturating_sub(watermark) >= MAX_SAFE_DISTANCE {
+        warn!(watermark, xmax = snapshot.xmax, "xmin watermark is too far behind the current xid, copying the whole table");
+        return None;
+    }
+    Some(format!("{} >= {}", row_xid8_expr(snapshot.xmax), watermark))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What row_xid8_expr computes on the source for a row's xmin
+    fn widen(xid: u32, xmax: u64) -> i64 {
+        let epochs = xid_epochs(xmax);
+        match xid {
+            xid if xid < FIRST_NORMAL_XID => 0,
+            xid if xid <= epochs.low => epochs.current + xid as i64,
+            xid => epochs.previous + xid as i64,
+        }
+    }
+
+    #[test]
+    fn xids_keep_their_value_in_the_first_epoch() {
+        assert_eq!(widen(1000, 5000), 1000);
+        assert_eq!(widen(5000, 5000), 5000);
+        // from transactions after the snapshot, never above a watermark
+        assert!(widen(6000, 5000) < 0);
+    }
+
+    #[test]
+    fn xids_before_the_wraparound_belong_to_the_previous_epoch() {
+        let xmax = XID_SPACE + 100;
+        assert_eq!(widen(50, xmax), (XID_SPACE + 50) as i64);
+        assert_eq!(widen(u32::MAX, xmax), (XID_SPACE - 1) as i64);
+        assert_eq!(widen(u32::MAX - 10, xmax), (XID_SPACE - 11) as i64);
+        // exactly at the epoch boundary
+        assert_eq!(xid_epochs(XID_SPACE), XidEpochs { low: 0, current: XID_SPACE as i64, previous: 0 });
+        assert_eq!(widen(u32::MAX, XID_SPACE), (XID_SPACE - 1) as i64);
+        assert_eq!(widen(u32::MAX, XID_SPACE - 1), (XID_SPACE - 1) as i64);
+    }
+
+    #[test]
+    fn frozen_and_special_xids_are_older_than_everything() {
+        for xid in 0..FIRST_NORMAL_XID {
+            assert_eq!(widen(xid, 5000), 0);
+            assert_eq!(widen(xid, 7 * XID_SPACE + 2), 0);
+        }
+        assert_eq!(widen(FIRST_NORMAL_XID, 7 * XID_SPACE + 2), (6 * XID_SPACE + 3) as i64);
+    }
+
+    #[test]
+    fn watermarks_too_far_behind_copy_everything() {
+        let snapshot = SourceSnapshot { xmin: 3 * XID_SPACE, xmax: 3 * XID_SPACE + 10 };
+        assert!(changed_since(None, &snapshot).is_none());
+        assert!(changed_since(Some(snapshot.xmax - MAX_SAFE_DISTANCE), &snapshot).is_none());
+        let condition = changed_since(Some(snapshot.xmax - MAX_SAFE_DISTANCE + 1), &snapshot).unwrap();
+        assert!(condition.ends_with(&format!(">= {}", snapshot.xmax - MAX_SAFE_DISTANCE + 1)), "{}", condition);
+        // a watermark ahead of the snapshot (restored target) isn't a reason for a full copy
+        assert!(changed_since(Some(snapshot.xmax + 5), &snapshot).is_some());
+    }
+}